@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tangibl::TokenCode;
+use topcodes::TopCode;
+
+/// Rows of programs covering a wall, each a start token followed by a chain of commands.
+fn wall(token_count: usize) -> Vec<TopCode> {
+    const ROW_LENGTH: usize = 20;
+    (0..token_count)
+        .map(|i| {
+            let (row, column) = (i / ROW_LENGTH, i % ROW_LENGTH);
+            let code = if column == 0 {
+                TokenCode::Start
+            } else {
+                TokenCode::MoveForwards
+            };
+            TopCode::mock(
+                code.value(),
+                6.0,
+                0.0,
+                column as f64 * 100.0,
+                row as f64 * 200.0,
+            )
+        })
+        .collect()
+}
+
+/// Parses walls of increasing size, through the spatial grid and through the baseline which checks
+/// every token for the candidates of each link.
+fn parse(c: &mut Criterion) {
+    parse_walls(c, "parse_report", tangibl::parse_report);
+    parse_walls(
+        c,
+        "parse_report_linear_scan",
+        tangibl::bench::parse_report_linear_scan,
+    );
+}
+
+fn parse_walls(c: &mut Criterion, name: &str, parse: fn(&[TopCode]) -> tangibl::ParseReport) {
+    let mut group = c.benchmark_group(name);
+    for token_count in [10, 100, 1_000, 5_000] {
+        let topcodes = wall(token_count);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(token_count),
+            &topcodes,
+            |b, topcodes| b.iter(|| parse(topcodes)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
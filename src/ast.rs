@@ -75,7 +75,67 @@ pub enum FlowKind {
     Conditional(Conditional),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+impl FlowKind {
+    /// The nested flow of the node, i.e. the body of a method or the alternate of a conditional.
+    pub fn branch(&self) -> Option<&Flow> {
+        match self {
+            FlowKind::Command(_) => None,
+            FlowKind::BooleanMethod(boolean_method) => boolean_method.body.as_deref(),
+            FlowKind::IntegerMethod(integer_method) => integer_method.body.as_deref(),
+            FlowKind::Conditional(conditional) => conditional.alternate.as_deref(),
+        }
+    }
+
+    pub(crate) fn branch_mut(&mut self) -> Option<&mut Option<Box<Flow>>> {
+        match self {
+            FlowKind::Command(_) => None,
+            FlowKind::BooleanMethod(boolean_method) => Some(&mut boolean_method.body),
+            FlowKind::IntegerMethod(integer_method) => Some(&mut integer_method.body),
+            FlowKind::Conditional(conditional) => Some(&mut conditional.alternate),
+        }
+    }
+
+    /// Compares everything but the branch.
+    fn shallow_eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (FlowKind::Command(a), FlowKind::Command(b)) => a == b,
+            (FlowKind::BooleanMethod(a), FlowKind::BooleanMethod(b)) => {
+                a.kind == b.kind && a.condition == b.condition
+            }
+            (FlowKind::IntegerMethod(a), FlowKind::IntegerMethod(b)) => {
+                a.kind == b.kind && a.value == b.value
+            }
+            (FlowKind::Conditional(a), FlowKind::Conditional(b)) => a.kind == b.kind,
+            _ => false,
+        }
+    }
+
+    /// Clones everything but the branch.
+    fn shallow_clone(&self) -> Self {
+        match self {
+            FlowKind::Command(command) => FlowKind::Command(*command),
+            FlowKind::BooleanMethod(boolean_method) => FlowKind::BooleanMethod(BooleanMethod {
+                kind: boolean_method.kind,
+                body: None,
+                condition: boolean_method.condition,
+            }),
+            FlowKind::IntegerMethod(integer_method) => FlowKind::IntegerMethod(IntegerMethod {
+                kind: integer_method.kind,
+                body: None,
+                value: integer_method.value,
+            }),
+            FlowKind::Conditional(conditional) => FlowKind::Conditional(Conditional {
+                kind: conditional.kind,
+                alternate: None,
+            }),
+        }
+    }
+}
+
+/// A node in the flow of the program. A line of tokens becomes a chain of nodes as long as the
+/// line, so cloning, comparing and dropping are implemented without recursion to avoid
+/// overflowing the stack.
+#[derive(Debug)]
 pub struct Flow {
     pub kind: FlowKind,
     pub next: Option<Box<Flow>>,
@@ -86,3 +146,133 @@ impl Flow {
         Self { kind, next: None }
     }
 }
+
+impl Clone for Flow {
+    fn clone(&self) -> Self {
+        // Children are cloned before their parents, so that they can be moved into place. The
+        // branch is pushed last so that it is cloned first, leaving the next node on top of the
+        // clones when the parent is assembled.
+        let mut pending = vec![(self, false)];
+        let mut clones: Vec<Box<Flow>> = Vec::new();
+        while let Some((flow, children_cloned)) = pending.pop() {
+            if !children_cloned {
+                pending.push((flow, true));
+                pending.extend(flow.next.as_deref().map(|next| (next, false)));
+                pending.extend(flow.kind.branch().map(|branch| (branch, false)));
+                continue;
+            }
+            let mut clone = Flow::new(flow.kind.shallow_clone());
+            if flow.next.is_some() {
+                clone.next = clones.pop();
+            }
+            if let Some(branch) = clone.kind.branch_mut() {
+                if flow.kind.branch().is_some() {
+                    *branch = clones.pop();
+                }
+            }
+            clones.push(Box::new(clone));
+        }
+        *clones.pop().expect("the root is cloned last")
+    }
+}
+
+impl PartialEq for Flow {
+    fn eq(&self, other: &Self) -> bool {
+        let mut pending = vec![(self, other)];
+        while let Some((a, b)) = pending.pop() {
+            if !a.kind.shallow_eq(&b.kind) {
+                return false;
+            }
+            for children in [
+                (a.kind.branch(), b.kind.branch()),
+                (a.next.as_deref(), b.next.as_deref()),
+            ] {
+                match children {
+                    (Some(a), Some(b)) => pending.push((a, b)),
+                    (None, None) => {}
+                    _ => return false,
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Eq for Flow {}
+
+impl Drop for Flow {
+    fn drop(&mut self) {
+        // Detaching the children before they are dropped means each drop only has to deal with
+        // a single node.
+        let mut pending = Vec::new();
+        let mut flow = self;
+        let mut owned;
+        loop {
+            pending.extend(flow.next.take());
+            if let Some(branch) = flow.kind.branch_mut() {
+                pending.extend(branch.take());
+            }
+            match pending.pop() {
+                Some(next) => {
+                    owned = next;
+                    flow = &mut owned;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A chain long enough to overflow the stack if any of the traversals were recursive.
+    fn long_flow() -> Flow {
+        let mut flow = Flow::new(FlowKind::Command(Command::Shoot));
+        for i in 0..100_000 {
+            let kind = if i % 2 == 0 {
+                FlowKind::IntegerMethod(IntegerMethod {
+                    kind: IntegerMethodKind::Repeat,
+                    body: Some(Box::new(Flow::new(FlowKind::Command(Command::TurnLeft)))),
+                    value: Some(Value::Two),
+                })
+            } else {
+                FlowKind::Command(Command::MoveForwards)
+            };
+            flow = Flow {
+                kind,
+                next: Some(Box::new(flow)),
+            };
+        }
+        flow
+    }
+
+    #[test]
+    fn it_can_clone_compare_and_drop_long_chains() {
+        let flow = long_flow();
+        let clone = flow.clone();
+        assert!(flow == clone);
+
+        let mut different = clone.clone();
+        let mut last = &mut different;
+        while last.next.is_some() {
+            last = last.next.as_mut().unwrap();
+        }
+        last.kind = FlowKind::Command(Command::TurnRight);
+        assert!(flow != different);
+    }
+
+    #[test]
+    fn it_compares_branches() {
+        let repeat = |body: Command| {
+            Flow::new(FlowKind::IntegerMethod(IntegerMethod {
+                kind: IntegerMethodKind::Repeat,
+                body: Some(Box::new(Flow::new(FlowKind::Command(body)))),
+                value: None,
+            }))
+        };
+        assert_eq!(repeat(Command::Shoot), repeat(Command::Shoot).clone());
+        assert_ne!(repeat(Command::Shoot), repeat(Command::TurnLeft));
+    }
+}
@@ -0,0 +1,11 @@
+//! Baselines for the benchmarks. Not part of the public API, and may change at any time.
+
+use topcodes::TopCode;
+
+use crate::{parser::Parser, ParseReport};
+
+/// Parses in the same way as [`crate::parse_report`], but finds the candidates for each link by
+/// checking every token rather than only the nearby ones.
+pub fn parse_report_linear_scan(topcodes: &[TopCode]) -> ParseReport {
+    Parser::new(topcodes).with_linear_scan().parse_report()
+}
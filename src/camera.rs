@@ -0,0 +1,539 @@
+use std::f64::consts::PI;
+
+use topcodes::TopCode;
+
+/// A perspective transform from image coordinates to table coordinates, for cameras which look at
+/// the table at an angle. Without it, tokens further from the camera look smaller and closer
+/// together, and the links between them fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Homography {
+    /// The row-major matrix, applied to (x, y, 1).
+    pub matrix: [[f64; 3]; 3],
+}
+
+impl Homography {
+    pub fn new(matrix: [[f64; 3]; 3]) -> Self {
+        Self { matrix }
+    }
+
+    /// Computes the homography which maps each of the image points to the table point at the same
+    /// index, or `None` if three of either set of points are in a line.
+    pub fn from_points(image: [(f64, f64); 4], table: [(f64, f64); 4]) -> Option<Self> {
+        // Each pair of points gives two equations in the first eight entries of the matrix, with
+        // the last entry fixed at 1.
+        let mut system = [[0.0; 9]; 8];
+        for (i, (&(x, y), &(u, v))) in image.iter().zip(&table).enumerate() {
+            system[i * 2] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+            system[i * 2 + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+        }
+        let h = solve(system)?;
+        let homography = Self::new([[h[0], h[1], h[2]], [h[3], h[4], h[5]], [h[6], h[7], 1.0]]);
+        // Collinear points leave the system solvable, but map something to infinity.
+        image
+            .iter()
+            .all(|&(x, y)| homography.apply(x, y).is_some())
+            .then_some(homography)
+    }
+
+    /// Computes the homography from four calibration markers at known positions on the table,
+    /// given as (code, x, y). Each marker is found in the scanned TopCodes by its code.
+    pub fn from_markers(topcodes: &[TopCode], markers: [(u32, f64, f64); 4]) -> Option<Self> {
+        let mut image = [(0.0, 0.0); 4];
+        for (point, &(code, _, _)) in image.iter_mut().zip(&markers) {
+            let marker = topcodes.iter().find(|topcode| topcode.code == Some(code))?;
+            *point = (marker.x, marker.y);
+        }
+        Self::from_points(image, markers.map(|(_, x, y)| (x, y)))
+    }
+
+    /// Maps a point in the image onto the table, or `None` if it maps to infinity, i.e. it is on
+    /// or beyond the horizon.
+    pub fn apply(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let [a, b, c] = self.matrix;
+        let w = c[0] * x + c[1] * y + c[2];
+        if w.abs() < f64::EPSILON {
+            return None;
+        }
+        let point = (
+            (a[0] * x + a[1] * y + a[2]) / w,
+            (b[0] * x + b[1] * y + b[2]) / w,
+        );
+        (point.0.is_finite() && point.1.is_finite()).then_some(point)
+    }
+
+    /// Maps a TopCode onto the table. The orientation and size are mapped by following a short
+    /// step from the centre of the TopCode along and across its orientation, since perspective
+    /// stretches each direction differently.
+    pub fn rectify(&self, topcode: &TopCode) -> Option<TopCode> {
+        map_topcode(topcode, |x, y| self.apply(x, y))
+    }
+}
+
+/// The Brown–Conrady model of a lens, which bends straight lines away from the centre of wide
+/// angle images. Positions are measured from the optical centre in units of the focal length, so
+/// that the coefficients do not depend on the resolution of the camera.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LensDistortion {
+    /// The optical centre of the image, usually its middle.
+    pub center_x: f64,
+    pub center_y: f64,
+    /// The focal length in pixels. Anything close, such as the width of the image, will do as
+    /// long as the coefficients were fitted with the same value.
+    pub focal_length: f64,
+    /// The radial coefficients.
+    pub k1: f64,
+    pub k2: f64,
+    /// The tangential coefficients, for lenses which are not quite parallel to the sensor.
+    pub p1: f64,
+    pub p2: f64,
+}
+
+impl LensDistortion {
+    /// A lens without any distortion, to be fitted with [`Self::calibrate`].
+    pub fn new(center_x: f64, center_y: f64, focal_length: f64) -> Self {
+        Self {
+            center_x,
+            center_y,
+            focal_length,
+            k1: 0.0,
+            k2: 0.0,
+            p1: 0.0,
+            p2: 0.0,
+        }
+    }
+
+    /// Where the lens moves an undistorted point to in the image.
+    pub fn distort(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = self.normalise(x, y);
+        let (x, y) = self.distort_normalised(x, y);
+        self.denormalise(x, y)
+    }
+
+    /// Where a point in the image would be without the lens distortion, or `None` if the model
+    /// does not converge there, e.g. far outside the image. The model has no closed form inverse,
+    /// so the point is found with Newton's method.
+    pub fn undistort(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let (distorted_x, distorted_y) = self.normalise(x, y);
+        let (mut x, mut y) = (distorted_x, distorted_y);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let (error_x, error_y) = self.distort_normalised(x, y);
+            let (error_x, error_y) = (error_x - distorted_x, error_y - distorted_y);
+            let [[a, b], [c, d]] = self.jacobian(x, y);
+            let determinant = a * d - b * c;
+            if determinant.abs() < f64::EPSILON {
+                return None;
+            }
+            x -= (d * error_x - b * error_y) / determinant;
+            y -= (a * error_y - c * error_x) / determinant;
+        }
+        let (check_x, check_y) = self.distort_normalised(x, y);
+        let error = (check_x - distorted_x).hypot(check_y - distorted_y);
+        (error < 1e-9).then(|| self.denormalise(x, y))
+    }
+
+    /// Distorts a TopCode's position, orientation and size as the lens would, e.g. to simulate the
+    /// camera.
+    pub fn distort_topcode(&self, topcode: &TopCode) -> Option<TopCode> {
+        map_topcode(topcode, |x, y| Some(self.distort(x, y)))
+    }
+
+    /// Undoes the distortion of a TopCode's position, orientation and size.
+    pub fn correct(&self, topcode: &TopCode) -> Option<TopCode> {
+        map_topcode(topcode, |x, y| self.undistort(x, y))
+    }
+
+    /// Fits the coefficients to scans of straight rows of tokens, so that each row is straight
+    /// again once corrected. The rows should run across the image away from the centre, where
+    /// they are bent the most, as a row through the centre is not bent at all. A single row, or
+    /// rows which are all parallel, only pin down the radial coefficients, so the tangential ones
+    /// are only fitted when at least two of the rows cross. Returns `None` if any row has fewer
+    /// than five TopCodes, or if no better fit than the current one was found.
+    pub fn calibrate(&self, rows: &[&[TopCode]]) -> Option<Self> {
+        let rows = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|topcode| self.normalise(topcode.x, topcode.y))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        if rows.is_empty() || rows.iter().any(|points| points.len() < 5) {
+            return None;
+        }
+        let angles = rows
+            .iter()
+            .map(|points| line_fit(points).1)
+            .collect::<Vec<_>>();
+        let crossing = angles.iter().any(|a| {
+            let difference = (a - angles[0]).rem_euclid(PI);
+            difference.min(PI - difference) > MIN_CROSSING_ANGLE
+        });
+        let start = [self.k1, self.k2, self.p1, self.p2];
+        let coefficients = fit(start, |[k1, k2, p1, p2]| {
+            // Without crossing rows the tangential coefficients stay where they are, so the fit
+            // leaves them alone.
+            let (p1, p2) = if crossing {
+                (p1, p2)
+            } else {
+                (self.p1, self.p2)
+            };
+            let lens = self.with_coefficients([k1, k2, p1, p2]);
+            let mut residuals = Vec::new();
+            for points in &rows {
+                let undistorted = points
+                    .iter()
+                    .map(|&(x, y)| {
+                        let (x, y) = lens.denormalise(x, y);
+                        let (x, y) = lens.undistort(x, y)?;
+                        Some(lens.normalise(x, y))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                residuals.extend(line_residuals(&undistorted));
+            }
+            Some(residuals)
+        })?;
+        Some(self.with_coefficients(coefficients))
+    }
+
+    fn with_coefficients(&self, [k1, k2, p1, p2]: [f64; 4]) -> Self {
+        Self {
+            k1,
+            k2,
+            p1,
+            p2,
+            ..*self
+        }
+    }
+
+    fn distort_normalised(&self, x: f64, y: f64) -> (f64, f64) {
+        let r2 = x * x + y * y;
+        let radial = 1.0 + self.k1 * r2 + self.k2 * r2 * r2;
+        let (dx, dy) = self.tangential(x, y, r2);
+        (x * radial + dx, y * radial + dy)
+    }
+
+    /// The derivatives of the distorted position with respect to the undistorted one.
+    fn jacobian(&self, x: f64, y: f64) -> [[f64; 2]; 2] {
+        let r2 = x * x + y * y;
+        let radial = 1.0 + self.k1 * r2 + self.k2 * r2 * r2;
+        let radial_slope = 2.0 * (self.k1 + 2.0 * self.k2 * r2);
+        let (radial_x, radial_y) = (radial_slope * x, radial_slope * y);
+        [
+            [
+                radial + x * radial_x + 2.0 * self.p1 * y + 6.0 * self.p2 * x,
+                x * radial_y + 2.0 * self.p1 * x + 2.0 * self.p2 * y,
+            ],
+            [
+                y * radial_x + 2.0 * self.p1 * x + 2.0 * self.p2 * y,
+                radial + y * radial_y + 6.0 * self.p1 * y + 2.0 * self.p2 * x,
+            ],
+        ]
+    }
+
+    fn tangential(&self, x: f64, y: f64, r2: f64) -> (f64, f64) {
+        (
+            2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x),
+            self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y,
+        )
+    }
+
+    fn normalise(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - self.center_x) / self.focal_length,
+            (y - self.center_y) / self.focal_length,
+        )
+    }
+
+    fn denormalise(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            x * self.focal_length + self.center_x,
+            y * self.focal_length + self.center_y,
+        )
+    }
+}
+
+const UNDISTORT_ITERATIONS: usize = 20;
+/// The smallest angle between two rows, in radians, for them to count as crossing in
+/// [`LensDistortion::calibrate`].
+const MIN_CROSSING_ANGLE: f64 = PI / 6.0;
+
+/// Maps a TopCode through a transform of the image. The orientation and size are mapped by
+/// following a short step from the centre of the TopCode along and across its orientation, since
+/// the transform may stretch each direction differently.
+fn map_topcode(
+    topcode: &TopCode,
+    transform: impl Fn(f64, f64) -> Option<(f64, f64)>,
+) -> Option<TopCode> {
+    let (x, y) = transform(topcode.x, topcode.y)?;
+    let (sin, cos) = topcode.orientation.sin_cos();
+    let (dx, dy) = (cos * topcode.unit, sin * topcode.unit);
+    let along = transform(topcode.x + dx, topcode.y + dy)?;
+    let across = transform(topcode.x - dy, topcode.y + dx)?;
+    let along_length = (along.0 - x).hypot(along.1 - y);
+    let across_length = (across.0 - x).hypot(across.1 - y);
+    let mut mapped = *topcode;
+    mapped.unit = (along_length * across_length).sqrt();
+    mapped.orientation = (along.1 - y).atan2(along.0 - x);
+    mapped.x = x;
+    mapped.y = y;
+    Some(mapped)
+}
+
+/// The distance of each point from the line which fits them best.
+fn line_residuals(points: &[(f64, f64)]) -> Vec<f64> {
+    let ((mean_x, mean_y), angle) = line_fit(points);
+    let (sin, cos) = angle.sin_cos();
+    points
+        .iter()
+        .map(|&(x, y)| (y - mean_y) * cos - (x - mean_x) * sin)
+        .collect()
+}
+
+/// The line which fits the points best, as a point on it and its angle.
+fn line_fit(points: &[(f64, f64)]) -> ((f64, f64), f64) {
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|point| point.0).sum::<f64>() / count;
+    let mean_y = points.iter().map(|point| point.1).sum::<f64>() / count;
+    let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+    for &(x, y) in points {
+        xx += (x - mean_x).powi(2);
+        yy += (y - mean_y).powi(2);
+        xy += (x - mean_x) * (y - mean_y);
+    }
+    ((mean_x, mean_y), 0.5 * (2.0 * xy).atan2(xx - yy))
+}
+
+/// Minimises the sum of the squared residuals with Levenberg–Marquardt, starting from the given
+/// parameters. The damping also keeps parameters which the residuals say little about close to
+/// where they started. Returns `None` unless the fit improved.
+fn fit(start: [f64; 4], residuals: impl Fn([f64; 4]) -> Option<Vec<f64>>) -> Option<[f64; 4]> {
+    const STEP: f64 = 1e-7;
+    let cost = |residuals: &[f64]| residuals.iter().map(|r| r * r).sum::<f64>();
+    let mut parameters = start;
+    let mut current = residuals(parameters)?;
+    let initial_cost = cost(&current);
+    let mut damping = 1e-3;
+    for _ in 0..100 {
+        // The Jacobian is estimated numerically, one parameter at a time.
+        let mut jacobian = [(); 4].map(|_| Vec::new());
+        for (i, column) in jacobian.iter_mut().enumerate() {
+            let mut stepped = parameters;
+            stepped[i] += STEP;
+            *column = residuals(stepped)?
+                .iter()
+                .zip(&current)
+                .map(|(a, b)| (a - b) / STEP)
+                .collect();
+        }
+        // The damped normal equations (JᵀJ + λI) δ = Jᵀr.
+        let mut normal = [[0.0; 5]; 4];
+        for (i, row) in normal.iter_mut().enumerate() {
+            for (j, entry) in row.iter_mut().take(4).enumerate() {
+                *entry = jacobian[i]
+                    .iter()
+                    .zip(&jacobian[j])
+                    .map(|(a, b)| a * b)
+                    .sum();
+            }
+            row[4] = jacobian[i].iter().zip(&current).map(|(a, b)| a * b).sum();
+        }
+
+        let mut improved = false;
+        while damping < 1e9 {
+            let mut system = normal;
+            for (i, row) in system.iter_mut().enumerate() {
+                row[i] += damping * (1.0 + row[i]);
+            }
+            let candidate = solve(system).map(|step| {
+                let mut candidate = parameters;
+                for (parameter, step) in candidate.iter_mut().zip(step) {
+                    *parameter -= step;
+                }
+                candidate
+            });
+            if let Some((candidate, candidate_residuals)) =
+                candidate.and_then(|candidate| Some((candidate, residuals(candidate)?)))
+            {
+                if cost(&candidate_residuals) < cost(&current) {
+                    parameters = candidate;
+                    current = candidate_residuals;
+                    damping /= 10.0;
+                    improved = true;
+                    break;
+                }
+            }
+            damping *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    (cost(&current) < initial_cost).then_some(parameters)
+}
+
+/// Solves the augmented system by Gaussian elimination, or `None` if it has no unique solution.
+fn solve<const N: usize, const M: usize>(mut system: [[f64; M]; N]) -> Option<[f64; N]> {
+    for column in 0..N {
+        let pivot = (column..N)
+            .max_by(|&a, &b| system[a][column].abs().total_cmp(&system[b][column].abs()))?;
+        if system[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        system.swap(column, pivot);
+        for row in 0..N {
+            if row == column {
+                continue;
+            }
+            let factor = system[row][column] / system[column][column];
+            let pivot_row = system[column];
+            for (entry, pivot_entry) in system[row].iter_mut().zip(pivot_row).skip(column) {
+                *entry -= factor * pivot_entry;
+            }
+        }
+    }
+    let mut solution = [0.0; N];
+    for (row, value) in solution.iter_mut().enumerate() {
+        *value = system[row][M - 1] / system[row][row];
+    }
+    solution
+        .iter()
+        .all(|value| value.is_finite())
+        .then_some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: [(f64, f64); 4] = [(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)];
+
+    #[test]
+    fn it_maps_the_points_it_was_computed_from() {
+        let image = [(10.0, 20.0), (90.0, 25.0), (120.0, 110.0), (-5.0, 100.0)];
+        let homography = Homography::from_points(image, SQUARE).unwrap();
+        for (&(x, y), &(u, v)) in image.iter().zip(&SQUARE) {
+            let (mapped_x, mapped_y) = homography.apply(x, y).unwrap();
+            assert!((mapped_x - u).abs() < 1e-9 && (mapped_y - v).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn it_rejects_collinear_points() {
+        let image = [(0.0, 0.0), (50.0, 0.0), (100.0, 0.0), (0.0, 100.0)];
+        assert_eq!(None, Homography::from_points(image, SQUARE));
+    }
+
+    #[test]
+    fn it_finds_the_calibration_markers() {
+        let topcodes = [
+            TopCode::mock(5, 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(9, 6.0, 0.0, 200.0, 0.0),
+            TopCode::mock(13, 6.0, 0.0, 200.0, 200.0),
+            TopCode::mock(17, 6.0, 0.0, 0.0, 200.0),
+        ];
+        let markers = [
+            (5, 0.0, 0.0),
+            (9, 100.0, 0.0),
+            (13, 100.0, 100.0),
+            (17, 0.0, 100.0),
+        ];
+        let homography = Homography::from_markers(&topcodes, markers).unwrap();
+        let rectified = homography
+            .rectify(&TopCode::mock(61, 6.0, 1.0, 50.0, 100.0))
+            .unwrap();
+        assert!((rectified.x - 25.0).abs() < 1e-9 && (rectified.y - 50.0).abs() < 1e-9);
+        assert!((rectified.unit - 3.0).abs() < 1e-9);
+        assert!((rectified.orientation - 1.0).abs() < 1e-9);
+
+        assert_eq!(None, Homography::from_markers(&topcodes[..3], markers));
+    }
+
+    fn wide_angle_lens() -> LensDistortion {
+        LensDistortion {
+            k1: -0.25,
+            k2: 0.05,
+            p1: 0.002,
+            p2: -0.001,
+            ..LensDistortion::new(320.0, 240.0, 400.0)
+        }
+    }
+
+    #[test]
+    fn it_undoes_the_lens_distortion() {
+        let lens = wide_angle_lens();
+        for (x, y) in [(320.0, 240.0), (0.0, 0.0), (600.0, 100.0), (100.0, 470.0)] {
+            let (distorted_x, distorted_y) = lens.distort(x, y);
+            let (undistorted_x, undistorted_y) = lens.undistort(distorted_x, distorted_y).unwrap();
+            assert!((undistorted_x - x).abs() < 1e-6 && (undistorted_y - y).abs() < 1e-6);
+        }
+    }
+
+    /// TopCodes along a straight line on the table, as seen through the lens.
+    fn row(lens: &LensDistortion, from: (f64, f64), to: (f64, f64)) -> Vec<TopCode> {
+        (0..8)
+            .map(|step| {
+                let t = step as f64 / 7.0;
+                let (x, y) =
+                    lens.distort(from.0 + (to.0 - from.0) * t, from.1 + (to.1 - from.1) * t);
+                TopCode::mock(55, 5.0, 0.0, x, y)
+            })
+            .collect()
+    }
+
+    /// How far the TopCodes are from a straight line once corrected by the lens.
+    fn straightness(lens: &LensDistortion, row: &[TopCode]) -> f64 {
+        let points = row
+            .iter()
+            .map(|topcode| lens.undistort(topcode.x, topcode.y).unwrap())
+            .collect::<Vec<_>>();
+        line_residuals(&points)
+            .iter()
+            .map(|residual| residual.abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn it_straightens_a_bent_row_of_tokens() {
+        let lens = LensDistortion {
+            k1: -0.25,
+            k2: 0.05,
+            ..LensDistortion::new(320.0, 240.0, 400.0)
+        };
+        let top = row(&lens, (40.0, 60.0), (600.0, 60.0));
+        // A row on the other side of the image, which is not used for the calibration.
+        let bottom = row(&lens, (40.0, 440.0), (600.0, 420.0));
+
+        let uncalibrated = LensDistortion::new(320.0, 240.0, 400.0);
+        assert!(straightness(&uncalibrated, &top) > 10.0);
+        assert!(straightness(&uncalibrated, &bottom) > 10.0);
+        let calibrated = uncalibrated.calibrate(&[&top]).unwrap();
+        assert!(straightness(&calibrated, &top) < 0.5);
+        assert!(straightness(&calibrated, &bottom) < 0.5);
+        // A single row says nothing about the tangential coefficients.
+        assert_eq!((0.0, 0.0), (calibrated.p1, calibrated.p2));
+
+        assert_eq!(None, uncalibrated.calibrate(&[&top[..4]]));
+        assert_eq!(None, uncalibrated.calibrate(&[]));
+    }
+
+    #[test]
+    fn it_fits_the_tangential_distortion_from_crossing_rows() {
+        let lens = wide_angle_lens();
+        let top = row(&lens, (40.0, 60.0), (600.0, 60.0));
+        let left = row(&lens, (60.0, 40.0), (60.0, 440.0));
+        let right = row(&lens, (580.0, 40.0), (580.0, 440.0));
+        let bottom = row(&lens, (40.0, 440.0), (600.0, 420.0));
+
+        let uncalibrated = LensDistortion::new(320.0, 240.0, 400.0);
+        let calibrated = uncalibrated.calibrate(&[&top, &left, &right]).unwrap();
+        assert!(straightness(&calibrated, &bottom) < 0.5);
+        for (expected, found) in [
+            (lens.k1, calibrated.k1),
+            (lens.k2, calibrated.k2),
+            (lens.p1, calibrated.p1),
+            (lens.p2, calibrated.p2),
+        ] {
+            assert!((expected - found).abs() < 1e-4, "{expected} != {found}");
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::{NodeId, Slot, TokenCode};
+
+/// How confident the parser is in one link of the parsed program, from 1 when the child sat
+/// exactly where the parent expected it, down to 0 when either its displacement or its angle is at
+/// the edge of the tolerance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConfidence {
+    pub parent: NodeId,
+    pub edge: Edge,
+    pub child: NodeId,
+    pub confidence: f64,
+}
+
+/// The field of the parent node which a link fills in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Edge {
+    /// The next flow, which for a conditional is also its 'true' path.
+    Next,
+    /// The body of a method.
+    Body,
+    /// The 'false' path of a conditional.
+    Alternate,
+    /// The condition of a boolean method.
+    Condition,
+    /// The value of an integer method.
+    Value,
+}
+
+impl Edge {
+    /// The edge filled in by the given slot of a token.
+    pub(crate) fn of(slot: Slot, owner: TokenCode) -> Self {
+        match slot {
+            Slot::Adjacent | Slot::True => Edge::Next,
+            Slot::Body => Edge::Body,
+            Slot::False => Edge::Alternate,
+            Slot::Parameter if owner == TokenCode::While => Edge::Condition,
+            Slot::Parameter => Edge::Value,
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use std::f64::consts::PI;
+
+use topcodes::TopCode;
+
+use crate::{Connection, Homography, LensDistortion, Slot, TokenCode, TokenShape, TokenShapes};
+
+// TODO: Commit some images from my honours explaining these measurements and how they relate to
+// each other, with a link to the image in source.
+
+/// An arbitrarily chosen value such that all the following values are in ratio. The original
+/// Tangibl tokens were designed in pixels with these measurements.
+const TOKEN_SIZE: f64 = 100.0;
+/// The radius of the actual TopCode circle.
+const TOPCODE_RADIUS: f64 = 24.0;
+/// The TopCode horizontal offset.
+const TOPCODE_CENTER_X: f64 = 50.0;
+/// The TopCode vertical offset. It is actually at 62, but counting from the other side is convenient for the trig.
+const TOPCODE_CENTER_Y: f64 = 38.0;
+/// The maximum displacement squared for a Token to be considered within an acceptable range of the previous
+/// token.
+const DISPLACEMENT_SQUARED_TOLERANCE: f64 = (TOPCODE_RADIUS * 2.5) * (TOPCODE_RADIUS * 2.5);
+/// The maximum angle deviation, in radians, from the expected angle of the previous token.
+const ANGLE_TOLERANCE: f64 = PI / 5.0;
+/// The point at which the 'true' and 'false' paths of conditional Tokens are closest in distance
+/// while still maintaining the same angle.
+const CONDITIONAL_INTERSECTION: f64 = 65.0;
+
+/// The tolerances and physical token geometry used by the parser. The defaults match the original
+/// Tangibl tokens, so this only needs to be changed for tokens printed with a different layout or
+/// for noisier cameras.
+///
+/// All lengths are in the same arbitrary unit as `token_size`, and are scaled to the size of the
+/// scanned TopCodes during parsing. The exception is the displacement tolerance, which is compared
+/// directly against scanned positions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParserConfig {
+    /// The width and height of a token.
+    pub token_size: f64,
+    /// The radius of the actual TopCode circle.
+    pub topcode_radius: f64,
+    /// The TopCode horizontal offset within the token.
+    pub topcode_center_x: f64,
+    /// The TopCode vertical offset within the token, measured from the side which is convenient
+    /// for the trig (the bottom edge when the token faces right).
+    pub topcode_center_y: f64,
+    /// The point at which the 'true' and 'false' paths of conditional tokens are closest in
+    /// distance while still maintaining the same angle.
+    pub conditional_intersection: f64,
+    /// The maximum displacement squared for a token to be considered within an acceptable range of
+    /// the position expected by the previous token.
+    pub displacement_squared_tolerance: f64,
+    /// The maximum angle deviation, in radians, from the angle expected by the previous token.
+    pub angle_tolerance: f64,
+    /// How the diameter of each token is derived from the size of the scanned TopCodes.
+    pub diameter: DiameterEstimate,
+    /// Whether to record every lookup in [`crate::ParseReport::trace`], to find out why a link was
+    /// or was not made.
+    pub trace: bool,
+    /// Where each kind of token connects to others. When unset, the shapes of the original
+    /// Tangibl tokens are derived from the geometry above. See [`Self::token_shapes`].
+    ///
+    /// Shapes only say where tokens connect, not what they look like, so the tokens drawn by
+    /// [`crate::render_svg`], [`crate::token_sheets`] and [`crate::render_image`] always have the
+    /// outlines of the original tokens.
+    pub shapes: Option<TokenShapes>,
+    /// Maps the scanned TopCodes onto the table before they are turned into tokens, for cameras
+    /// which look at the table at an angle. All lengths above are then in table units.
+    pub homography: Option<Homography>,
+    /// Undoes the distortion of a wide angle lens before the TopCodes are turned into tokens, and
+    /// before the homography is applied.
+    pub distortion: Option<LensDistortion>,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self {
+            token_size: TOKEN_SIZE,
+            topcode_radius: TOPCODE_RADIUS,
+            topcode_center_x: TOPCODE_CENTER_X,
+            topcode_center_y: TOPCODE_CENTER_Y,
+            conditional_intersection: CONDITIONAL_INTERSECTION,
+            displacement_squared_tolerance: DISPLACEMENT_SQUARED_TOLERANCE,
+            angle_tolerance: ANGLE_TOLERANCE,
+            diameter: DiameterEstimate::Median,
+            trace: false,
+            shapes: None,
+            homography: None,
+            distortion: None,
+        }
+    }
+}
+
+impl ParserConfig {
+    /// The diameter of the actual TopCode circle.
+    pub fn topcode_diameter(&self) -> f64 {
+        self.topcode_radius * 2.0
+    }
+
+    /// Corrects a scanned TopCode for the camera, or `None` if it cannot be placed on the table.
+    pub(crate) fn correct(&self, topcode: &TopCode) -> Option<TopCode> {
+        let topcode = match &self.distortion {
+            Some(distortion) => distortion.correct(topcode)?,
+            None => *topcode,
+        };
+        match &self.homography {
+            Some(homography) => homography.rectify(&topcode),
+            None => Some(topcode),
+        }
+    }
+
+    /// The shapes the parser links tokens with: [`Self::shapes`] if set, and otherwise the shapes of
+    /// the original Tangibl tokens. This is a good starting point for new shapes.
+    pub fn token_shapes(&self) -> TokenShapes {
+        if let Some(shapes) = &self.shapes {
+            return shapes.clone();
+        }
+        let connection = |slot, x, y, angle| Connection { slot, x, y, angle };
+        let adjacent = connection(Slot::Adjacent, self.token_size, 0.0, 0.0);
+        // The body starts a token's width along, at a right angle to the method.
+        let body_x = self.topcode_center_y - self.topcode_center_x;
+        let body = connection(Slot::Body, body_x, self.token_size + body_x, PI / 2.0);
+        let parameter = connection(Slot::Parameter, 0.0, -self.token_size, 0.0);
+        // The paths of a conditional leave at 45°, so the next token is a token's width further
+        // along each path.
+        let step = self.token_size * (PI / 4.0).cos();
+        let (true_x, true_y) = self.true_offset();
+        let (false_x, false_y) = self.false_offset();
+        let true_path = connection(Slot::True, true_x + step, true_y + step, PI / 4.0);
+        let false_path = connection(Slot::False, false_x + step, false_y - step, -PI / 4.0);
+
+        let mut shapes = TokenShapes::new();
+        for code in [
+            TokenCode::Start,
+            TokenCode::Shoot,
+            TokenCode::TurnLeft,
+            TokenCode::TurnRight,
+            TokenCode::MoveForwards,
+            TokenCode::MoveBackwards,
+        ] {
+            shapes.insert(code, TokenShape::new(vec![adjacent]));
+        }
+        for code in [TokenCode::Repeat, TokenCode::While] {
+            shapes.insert(code, TokenShape::new(vec![body, parameter, adjacent]));
+        }
+        shapes.insert(
+            TokenCode::Blocked,
+            TokenShape::new(vec![true_path, false_path]),
+        );
+        shapes
+    }
+
+    // The following are helper values for working with the conditional token, as it has a more
+    // complicated form-factor compared to the other Tangibl tokens.
+
+    /// The offset from the conditional TopCode to the point where the 'true' path leaves the token,
+    /// relative to the token's orientation.
+    fn true_offset(&self) -> (f64, f64) {
+        let hypotenuse = (self.topcode_center_x.powi(2) + self.topcode_center_y.powi(2)).sqrt();
+        let angle = (PI / 4.0) - (self.topcode_center_y / hypotenuse).asin();
+        (
+            hypotenuse * angle.cos() + self.conditional_intersection - self.topcode_center_x,
+            hypotenuse * angle.sin() + self.topcode_center_y,
+        )
+    }
+
+    /// The offset from the conditional TopCode to the point where the 'false' path leaves the
+    /// token, relative to the token's orientation.
+    fn false_offset(&self) -> (f64, f64) {
+        let opposite = self.token_size - self.topcode_center_y;
+        let hypotenuse = (self.topcode_center_x.powi(2) + opposite.powi(2)).sqrt();
+        let angle = (PI / 4.0) - (self.topcode_center_x / hypotenuse).asin();
+        (
+            hypotenuse * angle.cos() + self.conditional_intersection - self.topcode_center_x,
+            hypotenuse * angle.sin() - opposite,
+        )
+    }
+
+    /// The outline of a token, relative to its TopCode and orientation. Most tokens are square, but
+    /// the conditional has an arm along each path, which ends where the first token of the path
+    /// starts. Custom [`Self::shapes`] are not reflected here.
+    pub(crate) fn outline(&self, code: TokenCode) -> Vec<(f64, f64)> {
+        let (back, front) = (
+            -self.topcode_center_x,
+            self.token_size - self.topcode_center_x,
+        );
+        let (left, right) = (
+            self.topcode_center_y,
+            self.topcode_center_y - self.token_size,
+        );
+        if code != TokenCode::Blocked {
+            return vec![(back, left), (front, left), (front, right), (back, right)];
+        }
+        let step = self.token_size * (PI / 4.0).cos();
+        let fork = self.conditional_intersection - self.topcode_center_x;
+        // The inner edges of the arms meet halfway between the paths.
+        let inner = fork + step * 2.0 - self.token_size / 2.0;
+        vec![
+            (back, left),
+            (fork, left),
+            (fork + step, left + step),
+            (fork + step * 2.0, left),
+            (inner, (left + right) / 2.0),
+            (fork + step * 2.0, right),
+            (fork + step, right - step),
+            (fork, right),
+            (back, right),
+        ]
+    }
+}
+
+/// The settings of a [`crate::TemporalParser`], which parses a stream of frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TemporalConfig {
+    /// The config used to parse each frame.
+    pub parser: ParserConfig,
+    /// How far, in TopCode diameters, a token can move between frames and still be considered the
+    /// same token.
+    pub match_distance: f64,
+    /// How much weight, between 0 and 1, the latest detection of a token has over its previous
+    /// position. Lower values are smoother, but lag behind moving tokens.
+    pub smoothing: f64,
+    /// How many frames in a row a token can go undetected before it is removed from the scene.
+    pub max_missed_frames: usize,
+    /// How much the score of a link made in the previous frame is lowered by, so that it is only
+    /// replaced by a clearly better fit. Scores range from 0 for a perfect fit to 2 at the edge of
+    /// the tolerances.
+    pub link_hysteresis: f64,
+    /// How many frames in a row must produce the same program before it is emitted.
+    pub stable_frames: usize,
+}
+
+impl Default for TemporalConfig {
+    fn default() -> Self {
+        Self {
+            parser: ParserConfig::default(),
+            match_distance: 0.5,
+            smoothing: 0.5,
+            max_missed_frames: 3,
+            link_hysteresis: 0.25,
+            stable_frames: 5,
+        }
+    }
+}
+
+/// The settings of [`crate::token_sheets`], which draws tokens for printing. Lengths are in
+/// millimetres.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SheetConfig {
+    /// The geometry of the tokens, which is scaled so that tokens are printed at [`Self::token_size`].
+    /// See [`ParserConfig::shapes`] for custom shapes.
+    pub parser: ParserConfig,
+    /// The printed width and height of a token.
+    pub token_size: f64,
+    pub page_width: f64,
+    pub page_height: f64,
+    /// The space left blank around the edge of each page, which many printers cannot print on.
+    pub margin: f64,
+    /// The space between tokens, to leave room for cutting them out.
+    pub spacing: f64,
+}
+
+impl Default for SheetConfig {
+    /// Tokens 40mm across, on A4 paper.
+    fn default() -> Self {
+        Self {
+            parser: ParserConfig::default(),
+            token_size: 40.0,
+            page_width: 210.0,
+            page_height: 297.0,
+            margin: 10.0,
+            spacing: 4.0,
+        }
+    }
+}
+
+/// The settings of [`crate::render_image`], which draws tokens as a camera would see them, so that
+/// the whole pipeline from image to program can be tested.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageConfig {
+    /// The geometry of the tokens. See [`ParserConfig::shapes`] for custom shapes.
+    pub parser: ParserConfig,
+    /// The number of pixels per unit of the token geometry, e.g. a token is
+    /// `token_size * scale` pixels across at the bottom of the image.
+    pub scale: f64,
+    /// The space shown around the tokens, in the units of the token geometry.
+    pub margin: f64,
+    /// The most pixels the image may have, so that a stray token far from the others cannot use
+    /// up all of the memory.
+    pub max_pixels: usize,
+    /// The standard deviation of the Gaussian blur, in pixels.
+    pub blur: f64,
+    /// The standard deviation of the noise added to each pixel, in grey levels out of 255.
+    pub noise: f64,
+    /// How much darker the bottom right corner of the image is than the top left, between 0 and 1.
+    pub lighting: f64,
+    /// How much narrower the far (top) edge of the table looks than the near edge, between 0 and
+    /// 1, as for a camera which looks at the table at an angle. From around 0.3, the far TopCodes
+    /// are squashed enough that the scanner misreads them.
+    pub skew: f64,
+    /// Seeds the noise, so that the same image is drawn every time.
+    pub seed: u64,
+}
+
+impl Default for ImageConfig {
+    /// A slightly blurry and noisy camera straight above the table, with tokens 100 pixels across.
+    fn default() -> Self {
+        Self {
+            parser: ParserConfig::default(),
+            scale: 1.0,
+            margin: 50.0,
+            max_pixels: 4096 * 4096,
+            blur: 0.7,
+            noise: 4.0,
+            lighting: 0.1,
+            skew: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// The diameter of every TopCode is measured by the scanner, but individual measurements are
+/// noisy and the occasional misdetection can be wildly out. Since every link distance is scaled by
+/// the diameter, the parser normally replaces each measurement with a single estimate for the
+/// whole scene.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiameterEstimate {
+    /// The mean of every measurement. A single outlier skews the result.
+    Mean,
+    /// The median of every measurement.
+    Median,
+    /// The mean after discarding the given fraction (between 0 and 0.5) of the smallest and
+    /// largest measurements.
+    TrimmedMean(f64),
+    /// The mean after discarding measurements more than the given number of standard deviations
+    /// from the mean.
+    SigmaClippedMean(f64),
+    /// Every token keeps its own measurement. This suits scenes with perspective, where near
+    /// tokens appear larger than far ones, but is more sensitive to noise.
+    PerToken,
+}
+
+impl DiameterEstimate {
+    /// Estimates a single diameter from the measurements, or `None` if there are no measurements
+    /// or each token should keep its own.
+    pub fn estimate(&self, diameters: &[f64]) -> Option<f64> {
+        if diameters.is_empty() {
+            return None;
+        }
+        let mut sorted = diameters.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        match *self {
+            DiameterEstimate::Mean => Some(mean(&sorted)),
+            DiameterEstimate::Median => {
+                let middle = sorted.len() / 2;
+                if sorted.len() % 2 == 1 {
+                    Some(sorted[middle])
+                } else {
+                    Some((sorted[middle - 1] + sorted[middle]) / 2.0)
+                }
+            }
+            DiameterEstimate::TrimmedMean(fraction) => {
+                let trimmed = (sorted.len() as f64 * fraction.clamp(0.0, 0.5)).floor() as usize;
+                let kept = &sorted[trimmed..sorted.len() - trimmed];
+                // Trimming half from each end of an even number of measurements leaves nothing.
+                Some(if kept.is_empty() {
+                    mean(&sorted)
+                } else {
+                    mean(kept)
+                })
+            }
+            DiameterEstimate::SigmaClippedMean(sigmas) => {
+                let mean_all = mean(&sorted);
+                let variance = sorted
+                    .iter()
+                    .map(|diameter| (diameter - mean_all).powi(2))
+                    .sum::<f64>()
+                    / sorted.len() as f64;
+                let limit = sigmas * variance.sqrt();
+                let kept = sorted
+                    .into_iter()
+                    .filter(|diameter| (diameter - mean_all).abs() <= limit)
+                    .collect::<Vec<_>>();
+                Some(if kept.is_empty() {
+                    mean_all
+                } else {
+                    mean(&kept)
+                })
+            }
+            DiameterEstimate::PerToken => None,
+        }
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WITH_OUTLIER: [f64; 6] = [48.0, 50.0, 46.0, 49.0, 47.0, 480.0];
+
+    #[test]
+    fn the_mean_is_skewed_by_outliers() {
+        assert_eq!(Some(120.0), DiameterEstimate::Mean.estimate(&WITH_OUTLIER));
+    }
+
+    #[test]
+    fn robust_estimates_ignore_outliers() {
+        assert_eq!(Some(48.5), DiameterEstimate::Median.estimate(&WITH_OUTLIER));
+        assert_eq!(
+            Some(48.5),
+            DiameterEstimate::TrimmedMean(0.2).estimate(&WITH_OUTLIER)
+        );
+        assert_eq!(
+            Some(48.0),
+            DiameterEstimate::SigmaClippedMean(2.0).estimate(&WITH_OUTLIER)
+        );
+    }
+
+    #[test]
+    fn nothing_is_estimated_per_token_or_without_measurements() {
+        assert_eq!(None, DiameterEstimate::PerToken.estimate(&WITH_OUTLIER));
+        assert_eq!(None, DiameterEstimate::Median.estimate(&[]));
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+
+use crate::{ast::Start, LinkConfidence, ParseTrace, SourceMap, Span};
+
+/// The result of a parse, including everything the parser learned about the scene along the way.
+/// The AST is identical to the one returned by [`crate::parse`], while the diagnostics describe
+/// the tokens which could not be placed in it and the nodes which are incomplete.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseReport {
+    /// The parsed program, if a start token was found.
+    pub start: Option<Start>,
+    /// Everything which looked wrong during the parse, in the order it was encountered.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Where each node of the parsed program came from in the scene.
+    pub spans: SourceMap,
+    /// How well every link of the parsed program fit, in pre-order of the child nodes.
+    pub confidences: Vec<LinkConfidence>,
+    /// Every lookup made while linking the tokens, if enabled by [`crate::ParserConfig::trace`].
+    pub trace: Option<ParseTrace>,
+}
+
+impl ParseReport {
+    /// True if the parse produced a program and nothing looked wrong along the way.
+    pub fn is_clean(&self) -> bool {
+        self.start.is_some() && self.diagnostics.is_empty()
+    }
+
+    /// The confidence in the program as a whole, which is only as strong as its weakest link. A
+    /// lone start token is certain, while no program at all has no confidence.
+    pub fn confidence(&self) -> f64 {
+        if self.start.is_none() {
+            return 0.0;
+        }
+        self.confidences
+            .iter()
+            .map(|link| link.confidence)
+            .fold(1.0, f64::min)
+    }
+
+    /// All diagnostics of the given kind.
+    pub fn diagnostics_of(&self, kind: DiagnosticKind) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(move |diagnostic| diagnostic.kind == kind)
+    }
+}
+
+/// A single problem found while parsing, along with the tokens which caused it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// Where the offending tokens are in the scene, in the same coordinates as
+    /// [`ParseReport::spans`]. The first is always the token the diagnostic is about, e.g. the
+    /// method missing its parameter.
+    pub spans: Vec<Span>,
+    /// How well each token after the first fitted, for diagnostics which compare candidates (see
+    /// [`DiagnosticKind::Ambiguous`]). 0 is a perfect fit and 2 is at the edge of the tolerances.
+    /// Empty for every other kind.
+    pub scores: Vec<f64>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: DiagnosticKind, spans: Vec<Span>) -> Self {
+        Self {
+            kind,
+            spans,
+            scores: Vec::new(),
+        }
+    }
+
+    pub fn with_scores(mut self, scores: Vec<f64>) -> Self {
+        self.scores = scores;
+        self
+    }
+
+    /// The stable code for this diagnostic. See [`DiagnosticKind::code`].
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.code(), self.kind.description())?;
+        if let Some(span) = self.spans.first() {
+            write!(f, " ({:?} at {:.1}, {:.1})", span.code, span.x, span.y)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    /// There was no start token in the scene, so there is no program.
+    MissingStart,
+    /// There were several start tokens. Only the first was parsed, the others are listed.
+    DuplicateStart,
+    /// A recognised token which did not end up anywhere in the AST.
+    UnusedToken,
+    /// A method (e.g. Repeat or While) without its value or condition.
+    MissingParameter,
+    /// A method (e.g. Repeat or While) with nothing in its body.
+    MissingBody,
+    /// A conditional with nothing on its 'true' path.
+    MissingTrueBranch,
+    /// A token links back to one of its ancestors, so the layout loops back on itself. The chain
+    /// is cut at the repeated token, which is listed first, followed by the token linking to it.
+    Cycle,
+    /// A token fits more than one position. It is listed first, followed by the token it was
+    /// linked to (the best fit) and the token which missed out.
+    Conflict,
+    /// Several tokens fit the same position and nothing else claimed them, so the parser had to
+    /// guess. The owner of the position is listed first, followed by the competing tokens from
+    /// the best fit (the one linked) to the worst, with their scores in [`Diagnostic::scores`].
+    Ambiguous,
+}
+
+impl DiagnosticKind {
+    /// A short code which will not change between releases, so consumers can match on it (e.g.
+    /// for translations) without depending on the enum layout.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DiagnosticKind::MissingStart => "T001",
+            DiagnosticKind::DuplicateStart => "T002",
+            DiagnosticKind::UnusedToken => "T003",
+            DiagnosticKind::MissingParameter => "T004",
+            DiagnosticKind::MissingBody => "T005",
+            DiagnosticKind::MissingTrueBranch => "T006",
+            DiagnosticKind::Cycle => "T007",
+            DiagnosticKind::Conflict => "T008",
+            DiagnosticKind::Ambiguous => "T009",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            DiagnosticKind::MissingStart => "no start token was found",
+            DiagnosticKind::DuplicateStart => "more than one start token was found",
+            DiagnosticKind::UnusedToken => "token is not connected to the program",
+            DiagnosticKind::MissingParameter => "method is missing its parameter",
+            DiagnosticKind::MissingBody => "method has an empty body",
+            DiagnosticKind::MissingTrueBranch => "conditional has nothing on its true path",
+            DiagnosticKind::Cycle => "the tokens loop back on themselves",
+            DiagnosticKind::Conflict => "token could belong to more than one block",
+            DiagnosticKind::Ambiguous => "more than one token fits the same position",
+        }
+    }
+}
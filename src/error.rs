@@ -0,0 +1,117 @@
+use std::{error::Error, fmt};
+
+use crate::{Diagnostic, DiagnosticKind, Slot, Token, TokenCode};
+
+/// An error which stopped the parser from producing an AST. The parser never panics on a
+/// malformed scene, so anything unexpected about the input is surfaced through this type instead.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// There was no start token in the scene.
+    MissingStart,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingStart => write!(f, "no start token was found"),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::MissingStart => Diagnostic::new(DiagnosticKind::MissingStart, vec![]),
+        }
+    }
+}
+
+/// Token shapes which could not be loaded. See [`crate::TokenShapes`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShapeError {
+    message: String,
+}
+
+impl ShapeError {
+    pub(crate) fn new(message: impl fmt::Display) -> Self {
+        Self {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid token shapes: {}", self.message)
+    }
+}
+
+impl Error for ShapeError {}
+
+/// A program which could not be laid out. See [`crate::layout`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum LayoutError {
+    /// The shape of a token has no connection for part of the program, e.g. a custom shape
+    /// without a body.
+    MissingConnection { code: TokenCode, slot: Slot },
+    /// Two tokens would be placed on top of each other, e.g. when two branches cross.
+    Overlap(Token, Token),
+    /// A token would be placed where it also fits another slot, so the parser could link it
+    /// there instead.
+    Crowded {
+        owner: Token,
+        slot: Slot,
+        token: Token,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::MissingConnection { code, slot } => {
+                write!(f, "{:?} has no {:?} connection", code, slot)
+            }
+            LayoutError::Overlap(a, b) => {
+                write!(f, "{:?} and {:?} would overlap", a.code, b.code)
+            }
+            LayoutError::Crowded { owner, slot, token } => write!(
+                f,
+                "{:?} would also fit the {:?} slot of {:?}",
+                token.code, slot, owner.code
+            ),
+        }
+    }
+}
+
+impl Error for LayoutError {}
+
+/// Tokens which could not be drawn into an image. See [`crate::render_image`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImageError {
+    /// A setting of the [`crate::ImageConfig`] is out of range, e.g. a scale which is not
+    /// positive, or is not a number.
+    InvalidSetting { name: &'static str, value: f64 },
+    /// The image would have more than [`crate::ImageConfig::max_pixels`] pixels, e.g. because a
+    /// token is far away from the others.
+    TooLarge { width: f64, height: f64 },
+    /// The image would show nothing, as there are no tokens and no margin around them.
+    Empty,
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::InvalidSetting { name, value } => {
+                write!(f, "{} cannot be {}", name, value)
+            }
+            ImageError::TooLarge { width, height } => {
+                write!(f, "a {:.0}x{:.0} image would be too large", width, height)
+            }
+            ImageError::Empty => write!(f, "there is nothing to draw"),
+        }
+    }
+}
+
+impl Error for ImageError {}
@@ -0,0 +1,101 @@
+/// A uniform grid over the positions of the tokens, so that the tokens near a point can be found
+/// without checking every token in the scene.
+pub(crate) struct Grid {
+    cell_size: f64,
+    /// The cell of every point, as (row, column, index). Sorting by row first means the
+    /// neighbouring cells in a row are next to each other, so each row is a single range.
+    cells: Vec<(i64, i64, usize)>,
+}
+
+impl Grid {
+    /// Buckets the points into square cells of the given size. Searches should use a radius no
+    /// larger than the cell size, so that only the neighbouring cells need to be checked.
+    pub fn new(points: impl Iterator<Item = (f64, f64)>, cell_size: f64) -> Self {
+        // Without a usable cell size everything is bucketed into one cell, which is no worse than
+        // checking every token.
+        let cell_size = if cell_size.is_finite() && cell_size > 0.0 {
+            cell_size
+        } else {
+            f64::INFINITY
+        };
+        let mut grid = Self {
+            cell_size,
+            cells: Vec::new(),
+        };
+        for (index, (x, y)) in points.enumerate() {
+            let (column, row) = grid.cell(x, y);
+            grid.cells.push((row, column, index));
+        }
+        grid.cells.sort_unstable();
+        grid
+    }
+
+    fn cell(&self, x: f64, y: f64) -> (i64, i64) {
+        if self.cell_size.is_infinite() {
+            return (0, 0);
+        }
+        // The casts saturate, so far off points end up in the outermost cells.
+        (
+            (x / self.cell_size).floor() as i64,
+            (y / self.cell_size).floor() as i64,
+        )
+    }
+
+    /// The indices of every point which may be within the cell size of the given point, in
+    /// ascending order.
+    pub fn near(&self, x: f64, y: f64) -> Vec<usize> {
+        if x.is_nan() || y.is_nan() {
+            return Vec::new();
+        }
+        let (column, row) = self.cell(x, y);
+        let (first, last) = (column.saturating_sub(1), column.saturating_add(1));
+        let mut indices = Vec::new();
+        for row in row.saturating_sub(1)..=row.saturating_add(1) {
+            let start = self.cells.partition_point(|&cell| cell < (row, first, 0));
+            let end = self
+                .cells
+                .partition_point(|&cell| cell <= (row, last, usize::MAX));
+            indices.extend(self.cells[start..end].iter().map(|&(_, _, index)| index));
+        }
+        indices.sort_unstable();
+        indices
+    }
+
+    /// Whether [`Self::near`] would include a point at the given position, checked without the
+    /// index of cells so that the baseline of the benchmarks can scan every point instead.
+    pub fn is_near(&self, point: (f64, f64), x: f64, y: f64) -> bool {
+        if x.is_nan() || y.is_nan() {
+            return false;
+        }
+        let (column, row) = self.cell(x, y);
+        let (point_column, point_row) = self.cell(point.0, point.1);
+        column.abs_diff(point_column) <= 1 && row.abs_diff(point_row) <= 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_finds_points_in_neighbouring_cells() {
+        let points = [
+            (0.0, 0.0),
+            (15.0, 5.0),
+            (-9.0, -9.0),
+            (25.0, 0.0),
+            (0.0, -35.0),
+        ];
+        let grid = Grid::new(points.into_iter(), 10.0);
+        assert_eq!(vec![0, 1, 2], grid.near(5.0, 0.0));
+        assert_eq!(vec![1, 3], grid.near(25.0, 5.0));
+        assert_eq!(Vec::<usize>::new(), grid.near(f64::NAN, 0.0));
+    }
+
+    #[test]
+    fn it_falls_back_to_a_single_cell() {
+        let points = [(0.0, 0.0), (1e6, -1e6)];
+        let grid = Grid::new(points.into_iter(), f64::INFINITY);
+        assert_eq!(vec![0, 1], grid.near(-1e9, 0.0));
+    }
+}
@@ -0,0 +1,494 @@
+use std::{collections::HashMap, f64::consts::PI};
+
+use crate::{
+    ast::{BooleanMethod, Command, Condition, Conditional, FlowKind, IntegerMethod, Start, Value},
+    grid::Grid,
+    parser::Fit,
+    svg::to_image,
+    LayoutError, ParserConfig, Slot, Token, TokenCode, TokenShapes,
+};
+
+/// Places the tokens of a program where the parser expects to find them. The start token is at
+/// the origin facing right, and every other token is placed at the connection of the token it is
+/// linked from, so the whole layout follows from the shapes in the config.
+pub(crate) struct Layout<'a> {
+    config: &'a ParserConfig,
+    shapes: TokenShapes,
+    tokens: Vec<Token>,
+    /// The token placed in each slot.
+    links: HashMap<(usize, Slot), usize>,
+}
+
+impl<'a> Layout<'a> {
+    pub fn new(config: &'a ParserConfig) -> Self {
+        Self {
+            config,
+            shapes: config.token_shapes(),
+            tokens: Vec::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    /// Lays out the program, in pre-order. The walk keeps its own stack, like the parser, so
+    /// that long programs cannot overflow the stack.
+    pub fn place(mut self, start: &Start) -> Result<Vec<Token>, LayoutError> {
+        let diameter = self.config.topcode_diameter();
+        self.tokens
+            .push(Token::new(TokenCode::Start, diameter, 0.0, 0.0, 0.0));
+        let mut pending = Vec::new();
+        if let Some(next) = &start.next {
+            pending.push((0, Slot::Adjacent, next));
+        }
+        while let Some((owner, slot, flow)) = pending.pop() {
+            let index = self.place_token(owner, slot, code(&flow.kind))?;
+            // Pushed in reverse, so the branch is placed before the next flow.
+            let next_slot = match &flow.kind {
+                FlowKind::Conditional(_) => Slot::True,
+                _ => Slot::Adjacent,
+            };
+            if let Some(next) = &flow.next {
+                pending.push((index, next_slot, next));
+            }
+            match &flow.kind {
+                FlowKind::Command(_) => {}
+                FlowKind::Conditional(Conditional { alternate, .. }) => {
+                    if let Some(alternate) = alternate {
+                        pending.push((index, Slot::False, alternate));
+                    }
+                }
+                FlowKind::BooleanMethod(BooleanMethod {
+                    body, condition, ..
+                }) => {
+                    if let Some(condition) = condition {
+                        self.place_token(index, Slot::Parameter, condition_code(condition))?;
+                    }
+                    if let Some(body) = body {
+                        pending.push((index, Slot::Body, body));
+                    }
+                }
+                FlowKind::IntegerMethod(IntegerMethod { body, value, .. }) => {
+                    if let Some(value) = value {
+                        self.place_token(index, Slot::Parameter, value_code(value))?;
+                    }
+                    if let Some(body) = body {
+                        pending.push((index, Slot::Body, body));
+                    }
+                }
+            }
+        }
+        self.check()?;
+        Ok(self.tokens)
+    }
+
+    /// Places a token in the slot of the owner, returning its index.
+    fn place_token(
+        &mut self,
+        owner: usize,
+        slot: Slot,
+        code: TokenCode,
+    ) -> Result<usize, LayoutError> {
+        let token = self.tokens[owner];
+        let connection =
+            self.shapes
+                .connection(token.code, slot)
+                .ok_or(LayoutError::MissingConnection {
+                    code: token.code,
+                    slot,
+                })?;
+        let (x, y, angle) = connection.target(&token, self.config);
+        let index = self.tokens.len();
+        self.tokens.push(Token::new(
+            code,
+            token.diameter,
+            angle.rem_euclid(PI * 2.0),
+            x,
+            y,
+        ));
+        self.links.insert((owner, slot), index);
+        Ok(index)
+    }
+
+    /// Makes sure the parser would link every token to the slot it was placed in, and nowhere
+    /// else. Tokens which overlap, or which fit a slot they were not placed in, could be linked
+    /// differently.
+    fn check(&self) -> Result<(), LayoutError> {
+        let footprints = self
+            .tokens
+            .iter()
+            .map(|token| {
+                let ratio = token.ratio(self.config.topcode_diameter());
+                let outline = self
+                    .config
+                    .outline(token.code)
+                    .into_iter()
+                    .map(|point| to_image(token, point, ratio))
+                    .collect::<Vec<_>>();
+                ((token.x, -token.y), outline)
+            })
+            .collect::<Vec<_>>();
+        // Tokens can only overlap if their TopCodes are within twice the reach of a footprint.
+        let reach = self
+            .tokens
+            .iter()
+            .zip(&footprints)
+            .flat_map(|(token, (_, outline))| {
+                outline
+                    .iter()
+                    .map(|&(x, y)| (x - token.x).hypot(y + token.y))
+            })
+            .fold(0.0, f64::max);
+        let grid = Grid::new(
+            self.tokens.iter().map(|token| (token.x, token.y)),
+            f64::max(
+                self.config.displacement_squared_tolerance.sqrt(),
+                reach * 2.0,
+            ),
+        );
+        let tolerance = self.config.token_size * 1e-6;
+        for (index, token) in self.tokens.iter().enumerate() {
+            for other in grid.near(token.x, token.y) {
+                if other > index && overlaps(&footprints[index], &footprints[other], tolerance) {
+                    return Err(LayoutError::Overlap(*token, self.tokens[other]));
+                }
+            }
+            for &slot in Slot::of(token) {
+                let Some(connection) = self.shapes.connection(token.code, slot) else {
+                    continue;
+                };
+                let (x, y, angle) = connection.target(token, self.config);
+                let placed = self.links.get(&(index, slot));
+                for candidate in grid.near(x, y) {
+                    let other = &self.tokens[candidate];
+                    if candidate == index
+                        || placed == Some(&candidate)
+                        || !slot.accepts(token, other)
+                    {
+                        continue;
+                    }
+                    if Fit::new(other, x, y, angle).within_threshold(self.config) {
+                        return Err(LayoutError::Crowded {
+                            owner: *token,
+                            slot,
+                            token: *other,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the insides of two footprints overlap. Each footprint is the centre of the TopCode and
+/// the outline of the token around it. Outlines which only share an edge, as neighbouring tokens
+/// do, are kept apart by the tolerance.
+fn overlaps(a: &Footprint, b: &Footprint, tolerance: f64) -> bool {
+    let ((a_centre, a), (b_centre, b)) = (a, b);
+    let edges = |outline: &[(f64, f64)]| {
+        (0..outline.len())
+            .map(|index| (outline[index], outline[(index + 1) % outline.len()]))
+            .collect::<Vec<_>>()
+    };
+    let (a_edges, b_edges) = (edges(a), edges(b));
+    // The side of the line through p and q which r is on, scaled by the length of the line so that
+    // it is the distance from the line.
+    let side = |(p, q): Edge, r: (f64, f64)| {
+        ((q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)) / (q.0 - p.0).hypot(q.1 - p.1)
+    };
+    let crosses = a_edges.iter().any(|&a_edge| {
+        b_edges.iter().any(|&b_edge| {
+            side(a_edge, b_edge.0) * side(a_edge, b_edge.1) < -tolerance * tolerance
+                && side(b_edge, a_edge.0) * side(b_edge, a_edge.1) < -tolerance * tolerance
+        })
+    });
+    // A point well inside the outline, and not just on its edge.
+    let inside = |edges: &[Edge], (x, y): (f64, f64)| {
+        let mut inside = false;
+        for &((ax, ay), (bx, by)) in edges {
+            let t = (((x - ax) * (bx - ax) + (y - ay) * (by - ay))
+                / ((bx - ax).powi(2) + (by - ay).powi(2)))
+            .clamp(0.0, 1.0);
+            if (ax + (bx - ax) * t - x).hypot(ay + (by - ay) * t - y) <= tolerance {
+                return false;
+            }
+            if (ay > y) != (by > y) && x < ax + (bx - ax) * (y - ay) / (by - ay) {
+                inside = !inside;
+            }
+        }
+        inside
+    };
+    // The centre, the corners and the middle of every edge, which covers outlines that are on top
+    // of each other, or overlap along an edge without any edges crossing.
+    let points = |centre: (f64, f64), edges: &[Edge]| {
+        edges
+            .iter()
+            .flat_map(|&(p, q)| [p, ((p.0 + q.0) / 2.0, (p.1 + q.1) / 2.0)])
+            .chain([centre])
+            .collect::<Vec<_>>()
+    };
+    crosses
+        || points(*a_centre, &a_edges)
+            .into_iter()
+            .any(|point| inside(&b_edges, point))
+        || points(*b_centre, &b_edges)
+            .into_iter()
+            .any(|point| inside(&a_edges, point))
+}
+
+type Footprint = ((f64, f64), Vec<(f64, f64)>);
+type Edge = ((f64, f64), (f64, f64));
+
+fn code(kind: &FlowKind) -> TokenCode {
+    match kind {
+        FlowKind::Command(Command::MoveBackwards) => TokenCode::MoveBackwards,
+        FlowKind::Command(Command::MoveForwards) => TokenCode::MoveForwards,
+        FlowKind::Command(Command::Shoot) => TokenCode::Shoot,
+        FlowKind::Command(Command::TurnLeft) => TokenCode::TurnLeft,
+        FlowKind::Command(Command::TurnRight) => TokenCode::TurnRight,
+        FlowKind::BooleanMethod(_) => TokenCode::While,
+        FlowKind::IntegerMethod(_) => TokenCode::Repeat,
+        FlowKind::Conditional(_) => TokenCode::Blocked,
+    }
+}
+
+fn condition_code(condition: &Condition) -> TokenCode {
+    match condition {
+        Condition::IsBlocked => TokenCode::IsBlocked,
+        Condition::IsPathClear => TokenCode::IsPathClear,
+    }
+}
+
+fn value_code(value: &Value) -> TokenCode {
+    match value {
+        Value::One => TokenCode::Value1,
+        Value::Two => TokenCode::Value2,
+        Value::Three => TokenCode::Value3,
+        Value::Four => TokenCode::Value4,
+        Value::Five => TokenCode::Value5,
+        Value::Six => TokenCode::Value6,
+        Value::Seven => TokenCode::Value7,
+        Value::Eight => TokenCode::Value8,
+        Value::Infinity => TokenCode::ValueInfinite,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::{BooleanMethodKind, ConditionalKind, Flow, IntegerMethodKind},
+        flow, layout, layout_tokens, parse, start, Connection, TokenShape,
+    };
+    use proptest::prelude::*;
+
+    #[test]
+    fn it_lays_out_a_program_which_parses_back() {
+        let program = start()
+            .with_integer_method(
+                IntegerMethodKind::Repeat,
+                Some(Value::Three),
+                flow()
+                    .with_command(Command::MoveForwards)
+                    .with_command(Command::TurnLeft)
+                    .build(),
+            )
+            .with_conditional(
+                ConditionalKind::Blocked,
+                flow().with_command(Command::TurnRight).build(),
+            )
+            .with_command(Command::Shoot)
+            .build();
+        let topcodes = layout(&program).unwrap();
+        assert_eq!(8, topcodes.len());
+        assert_eq!(Some(program), parse(&topcodes));
+
+        let program = start()
+            .with_boolean_method(
+                BooleanMethodKind::While,
+                Some(Condition::IsPathClear),
+                flow().with_command(Command::MoveBackwards).build(),
+            )
+            .build();
+        assert_eq!(Some(program.clone()), parse(&layout(&program).unwrap()));
+    }
+
+    #[test]
+    fn it_rejects_branches_which_cross() {
+        // Each body turns a quarter further, so the fifth repeat lands on the first.
+        let mut body = None;
+        for _ in 0..5 {
+            body = flow()
+                .with_integer_method(IntegerMethodKind::Repeat, None, body)
+                .build();
+        }
+        let program = Start { next: body };
+        assert!(matches!(
+            Layout::new(&ParserConfig::default()).place(&program),
+            Err(LayoutError::Overlap(..))
+        ));
+    }
+
+    #[test]
+    fn it_rejects_tokens_whose_outlines_overlap() {
+        let config = ParserConfig::default();
+        let mut layout = Layout::new(&config);
+        // The TopCodes are further apart than their diameter, but the tokens still overlap.
+        layout.tokens = vec![
+            Token::new(TokenCode::Start, 48.0, 0.0, 0.0, 0.0),
+            Token::new(TokenCode::Shoot, 48.0, 0.0, 70.0, 0.0),
+        ];
+        layout.links.insert((0, Slot::Adjacent), 1);
+        assert!(matches!(layout.check(), Err(LayoutError::Overlap(..))));
+        // Tokens side by side only share an edge.
+        layout.tokens[1].x = 100.0;
+        assert_eq!(Ok(()), layout.check());
+    }
+
+    #[test]
+    fn it_needs_a_connection_for_every_link() {
+        let mut shapes = ParserConfig::default().token_shapes();
+        shapes.insert(
+            TokenCode::Repeat,
+            TokenShape::new(vec![Connection {
+                slot: Slot::Adjacent,
+                x: 100.0,
+                y: 0.0,
+                angle: 0.0,
+            }]),
+        );
+        let config = ParserConfig {
+            shapes: Some(shapes),
+            ..Default::default()
+        };
+        let program = start()
+            .with_integer_method(IntegerMethodKind::Repeat, Some(Value::Two), None)
+            .build();
+        assert_eq!(
+            Err(LayoutError::MissingConnection {
+                code: TokenCode::Repeat,
+                slot: Slot::Parameter
+            }),
+            Layout::new(&config).place(&program)
+        );
+    }
+
+    fn command() -> impl Strategy<Value = Command> {
+        prop::sample::select(vec![
+            Command::MoveBackwards,
+            Command::MoveForwards,
+            Command::Shoot,
+            Command::TurnLeft,
+            Command::TurnRight,
+        ])
+    }
+
+    fn condition() -> impl Strategy<Value = Option<Condition>> {
+        prop::option::of(prop::sample::select(vec![
+            Condition::IsBlocked,
+            Condition::IsPathClear,
+        ]))
+    }
+
+    fn value() -> impl Strategy<Value = Option<Value>> {
+        prop::option::of(prop::sample::select(vec![
+            Value::One,
+            Value::Two,
+            Value::Three,
+            Value::Four,
+            Value::Five,
+            Value::Six,
+            Value::Seven,
+            Value::Eight,
+            Value::Infinity,
+        ]))
+    }
+
+    /// A flow of up to a few nodes of any kind, nested a few levels deep.
+    fn program() -> impl Strategy<Value = Start> {
+        let leaf = prop::collection::vec(command().prop_map(FlowKind::Command), 1..4);
+        let kinds = leaf.prop_recursive(4, 32, 4, |inner| {
+            // Bodies and alternates are often left empty.
+            let branch = prop::option::of(inner.prop_map(chain)).prop_map(Option::flatten);
+            prop::collection::vec(
+                prop_oneof![
+                    3 => command().prop_map(FlowKind::Command),
+                    1 => (condition(), branch.clone()).prop_map(|(condition, body)| {
+                        FlowKind::BooleanMethod(BooleanMethod {
+                            kind: BooleanMethodKind::While,
+                            body,
+                            condition,
+                        })
+                    }),
+                    1 => (value(), branch.clone()).prop_map(|(value, body)| {
+                        FlowKind::IntegerMethod(IntegerMethod {
+                            kind: IntegerMethodKind::Repeat,
+                            body,
+                            value,
+                        })
+                    }),
+                    1 => branch.prop_map(|alternate| {
+                        FlowKind::Conditional(Conditional {
+                            kind: ConditionalKind::Blocked,
+                            alternate,
+                        })
+                    }),
+                ],
+                1..5,
+            )
+        });
+        kinds.prop_map(|kinds| Start {
+            next: chain(kinds).map(|flow| *flow),
+        })
+    }
+
+    fn chain(kinds: Vec<FlowKind>) -> Option<Box<Flow>> {
+        kinds
+            .into_iter()
+            .rev()
+            .fold(None, |next, kind| Some(Box::new(Flow { kind, next })))
+    }
+
+    proptest! {
+        #[test]
+        fn it_parses_a_noisy_layout_back_into_the_program(
+            program in program(),
+            angle in -PI..PI,
+            (offset_x, offset_y) in (-500.0..500.0, -500.0..500.0),
+            noise in prop::collection::vec((-4.0..4.0, -4.0..4.0, -0.08..0.08, any::<u32>()), 64),
+        ) {
+            // Branches may cross or crowd each other, which the layout must report rather than
+            // placing tokens the parser would misread.
+            let tokens = match layout_tokens(&program) {
+                Ok(tokens) => tokens,
+                Err(error) => {
+                    prop_assert!(
+                        matches!(error, LayoutError::Overlap(..) | LayoutError::Crowded { .. }),
+                        "{}",
+                        error
+                    );
+                    return Ok(());
+                }
+            };
+            // The whole layout is moved and turned, and then each token is nudged a little, as
+            // if placed by hand.
+            let (sin_angle, cos_angle) = angle.sin_cos();
+            let mut topcodes = tokens
+                .iter()
+                .zip(noise.iter().cycle())
+                .map(|(token, (dx, dy, da, key))| {
+                    let mut token = *token;
+                    (token.x, token.y) = (
+                        token.x * cos_angle - token.y * sin_angle + offset_x + dx,
+                        token.x * sin_angle + token.y * cos_angle + offset_y + dy,
+                    );
+                    token.orientation += angle + da;
+                    (*key, token.to_topcode())
+                })
+                .collect::<Vec<_>>();
+            // Sorting by the random keys shuffles the TopCodes.
+            topcodes.sort_by_key(|(key, _)| *key);
+            let topcodes = topcodes.into_iter().map(|(_, topcode)| topcode).collect::<Vec<_>>();
+            prop_assert_eq!(Some(program), parse(&topcodes));
+        }
+    }
+}
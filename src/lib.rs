@@ -1,12 +1,40 @@
+#[doc(hidden)]
+pub mod bench;
+mod camera;
+mod confidence;
+mod config;
+mod diagnostics;
+mod error;
+mod grid;
+mod layout;
 mod parser;
+mod raster;
+mod scene;
+mod shapes;
+mod spans;
+mod svg;
 mod tangibl;
+mod temporal;
 mod tokens;
+mod trace;
 mod visitor;
 mod visitors;
 
 pub mod ast;
 
 pub use crate::tangibl::*;
+pub use camera::*;
+pub use confidence::*;
+pub use config::*;
+pub use diagnostics::*;
+pub use error::*;
+pub use parser::Slot;
+pub use raster::SceneImage;
+pub use scene::*;
+pub use shapes::*;
+pub use spans::*;
+pub use temporal::*;
 pub use tokens::*;
+pub use trace::*;
 pub use visitor::*;
 pub use visitors::*;
@@ -1,6 +1,10 @@
-use std::f64::{self, consts::PI};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    f64::{self, consts::PI},
+};
 
-use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use topcodes::TopCode;
 
 use crate::{
@@ -8,245 +12,836 @@ use crate::{
         BooleanMethod, BooleanMethodKind, Command, Condition, Conditional, ConditionalKind, Flow,
         FlowKind, IntegerMethod, IntegerMethodKind, Start, Value,
     },
-    Token, TokenCode,
+    grid::Grid,
+    CandidateTrace, Diagnostic, DiagnosticKind, Edge, Fragment, LinkConfidence, LookupTrace,
+    NodeId, ParseError, ParseReport, ParseTrace, ParserConfig, Program, Scene, SourceMap, Span,
+    Token, TokenCode, TokenShapes, Verdict,
 };
 
-// TODO: Commit some images from my honours explaining these measurements and how they relate to
-// each other, with a link to the image in source.
-
-/// An arbitrarily chosen value such that all the following values are in ratio. The original
-/// Tangibl tokens were designed in pixels with these measurements.
-const TOKEN_SIZE: f64 = 100.0;
-/// The radius of the actual TopCode circle.
-const TOPCODE_RADIUS: f64 = 24.0;
-/// The diameter of the actual TopCode circle.
-const TOPCODE_DIAMETER: f64 = TOPCODE_RADIUS * 2.0;
-/// The TopCode horizontal offset.
-const TOPCODE_CENTER_X: f64 = 50.0;
-/// The TopCode vertical offset. It is actually at 62, but counting from the other side is convenient for the trig.
-const TOPCODE_CENTER_Y: f64 = 38.0;
-/// The maximum displacement squared for a Token to be considered within an acceptable range of the previous
-/// token.
-const DISPLACEMENT_SQUARED_TOLERANCE: f64 = (TOPCODE_RADIUS * 2.5) * (TOPCODE_RADIUS * 2.5);
-/// The maximum angle deviation, in radians, from the expected angle of the previous token.
-const ANGLE_TOLERANCE: f64 = PI / 5.0;
 const TWO_PI: f64 = PI * 2.0;
 
-// The following are pre-calculated helper values for working with the conditional token, as it has
-// a more complicated form-factor compared to the other Tangibl tokens.
-/// The point at which the 'true' and 'false' paths of conditional Tokens are closest in distance
-/// while still maintaining the same angle.
-const CONDITIONAL_INTERSECTION: f64 = 65.0;
-// Additionally, consts cannot be used until the following issue is resolved in the Rust standard
-// library (Since floats behave differently on many platforms):
-//
-// https://github.com/rust-Lang/rust/issues/57241
-lazy_static! {
-    static ref TRUE_HYPOTENUSE: f64 = (TOPCODE_CENTER_X.powi(2) + TOPCODE_CENTER_Y.powi(2)).sqrt();
-    static ref TRUE_TOKEN_X: f64 = *TRUE_HYPOTENUSE
-        * ((PI / 4.0) - (TOPCODE_CENTER_Y / *TRUE_HYPOTENUSE).asin()).cos()
-        + CONDITIONAL_INTERSECTION
-        - TOPCODE_CENTER_X;
-    static ref TRUE_TOKEN_Y: f64 = *TRUE_HYPOTENUSE
-        * ((PI / 4.0) - (TOPCODE_CENTER_Y / *TRUE_HYPOTENUSE).asin()).sin()
-        + TOPCODE_CENTER_Y;
-    static ref FALSE_HYPOTENUSE: f64 =
-        (TOPCODE_CENTER_X.powi(2) + (TOKEN_SIZE - TOPCODE_CENTER_Y).powi(2)).sqrt();
-    static ref FALSE_TOKEN_X: f64 = *FALSE_HYPOTENUSE
-        * ((PI / 4.0) - (TOPCODE_CENTER_X / *FALSE_HYPOTENUSE).asin()).cos()
-        + CONDITIONAL_INTERSECTION
-        - TOPCODE_CENTER_X;
-    static ref FALSE_TOKEN_Y: f64 = *FALSE_HYPOTENUSE
-        * ((PI / 4.0) - (TOPCODE_CENTER_X / *FALSE_HYPOTENUSE).asin()).sin()
-        - (TOKEN_SIZE - TOPCODE_CENTER_Y);
+/// The positions around a token at which another token can be linked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Slot {
+    /// The next token in the flow.
+    Adjacent,
+    /// The first token in the body of a method.
+    Body,
+    /// The value or condition of a method.
+    Parameter,
+    /// The first token on the 'true' path of a conditional.
+    True,
+    /// The first token on the 'false' path of a conditional.
+    False,
+}
+
+impl Slot {
+    /// The slots of the given token, in the order they are looked up.
+    pub(crate) fn of(token: &Token) -> &'static [Slot] {
+        match token.code {
+            TokenCode::Start
+            | TokenCode::Shoot
+            | TokenCode::TurnLeft
+            | TokenCode::TurnRight
+            | TokenCode::MoveForwards
+            | TokenCode::MoveBackwards => &[Slot::Adjacent],
+            TokenCode::Blocked => &[Slot::True, Slot::False],
+            TokenCode::Repeat | TokenCode::While => &[Slot::Body, Slot::Parameter, Slot::Adjacent],
+            _ => &[],
+        }
+    }
+
+    /// Whether the candidate can go in this slot of the owner.
+    pub(crate) fn accepts(&self, owner: &Token, candidate: &Token) -> bool {
+        match self {
+            Slot::Parameter if owner.code == TokenCode::While => candidate.is_condition(),
+            Slot::Parameter => candidate.is_value(),
+            _ => candidate.is_flow(),
+        }
+    }
+}
+
+/// A link from the slot of one token to another, as (owner, slot, target).
+pub(crate) type Link = (usize, Slot, usize);
+
+/// How closely a candidate token matches the position and angle a slot expects.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fit {
+    pub displacement_squared: f64,
+    /// The smallest angle, in radians, between the candidate and the expected orientation.
+    pub angle: f64,
+}
+
+impl Fit {
+    /// How closely the candidate matches the expected position and angle.
+    pub fn new(candidate: &Token, x: f64, y: f64, angle: f64) -> Self {
+        let displacement_squared = (candidate.x - x).powi(2) + (candidate.y - y).powi(2);
+        let mut delta_angle = (candidate.orientation - angle) % TWO_PI;
+        if delta_angle < 0.0 {
+            delta_angle += TWO_PI;
+        }
+        Self {
+            displacement_squared,
+            angle: f64::min(delta_angle, TWO_PI - delta_angle),
+        }
+    }
+
+    pub fn within_threshold(&self, config: &ParserConfig) -> bool {
+        self.displacement_squared <= config.displacement_squared_tolerance
+            && self.angle <= config.angle_tolerance
+    }
+
+    /// The displacement and angle error relative to their tolerances, so that a score of 0 is a
+    /// perfect fit and anything within threshold scores at most 2.
+    pub fn score(&self, config: &ParserConfig) -> f64 {
+        (self.displacement_squared / config.displacement_squared_tolerance).sqrt()
+            + self.angle / config.angle_tolerance
+    }
+
+    /// 1 for a perfect fit, down to 0 when either the displacement or the angle reaches the edge
+    /// of its tolerance.
+    pub fn confidence(&self, config: &ParserConfig) -> f64 {
+        let displacement =
+            (self.displacement_squared / config.displacement_squared_tolerance).sqrt();
+        let angle = self.angle / config.angle_tolerance;
+        (1.0 - displacement.max(angle)).clamp(0.0, 1.0)
+    }
+}
+
+/// The position and angle a slot expects, and every nearby candidate for it, best fit first.
+/// Candidates outside of the tolerances are included, so that they can be traced.
+pub(crate) struct Lookup {
+    pub x: f64,
+    pub y: f64,
+    pub angle: f64,
+    pub candidates: Vec<(usize, Fit)>,
+}
+
+/// A candidate for one of the slots of the owner. The rank is the position of the candidate when
+/// the slot's candidates are ordered from best to worst fit.
+#[derive(Clone, Copy, Debug)]
+struct Proposal {
+    owner: usize,
+    slot: Slot,
+    target: usize,
+    fit: Fit,
+    rank: usize,
+}
+
+/// A step of the walk which turns the links into an AST.
+enum Step {
+    /// Parses the flow linked from the parent, if any.
+    Link {
+        parent: usize,
+        token: Option<usize>,
+    },
+    /// Reports the method if the body which was just parsed is missing.
+    CheckBody(usize),
+    Report(DiagnosticKind, usize),
+    /// Attaches the branch and next flow, which were the last flows parsed, to the node.
+    Finish {
+        index: usize,
+        kind: FlowKind,
+    },
 }
 
 pub(crate) struct Parser {
+    config: ParserConfig,
     tokens: Vec<Token>,
+    /// The positions of the tokens, for finding the candidates near an expected position.
+    grid: Grid,
+    /// Whether to check every token for candidates instead, as a baseline for the benchmarks.
+    linear_scan: bool,
+    /// The index of the TopCode each token was read from.
+    origins: Vec<usize>,
+    /// The token linked from each slot. Every token is linked from at most one slot, so it can
+    /// only appear once in the AST.
+    links: HashMap<(usize, Slot), usize>,
+    /// The token whose slot each token is linked from, if any.
+    parents: Vec<Option<usize>>,
+    /// The slot each token is linked from, and how well the token fit it.
+    fits: Vec<Option<(Slot, Fit)>>,
+    /// The node each token was parsed into, if any.
+    nodes: Vec<Option<NodeId>>,
+    /// Whether each token has been placed in the AST. This is used to report the tokens which
+    /// were recognised, but never connected to the program.
+    used: Vec<bool>,
+    /// Whether each token is currently being parsed, i.e. is an ancestor of the current node.
+    /// Linking to one of these tokens would loop back on the current chain, so the chain is cut
+    /// there instead.
+    visiting: Vec<bool>,
+    diagnostics: Vec<Diagnostic>,
+    spans: SourceMap,
+    confidences: Vec<LinkConfidence>,
+    /// Links which should win close contests, as (owner, slot, target).
+    preferred: HashSet<Link>,
+    /// How much the score of a preferred link is lowered by.
+    preference: f64,
+    /// Where each kind of token connects to others.
+    shapes: TokenShapes,
+    /// Every lookup made while resolving the links, if enabled by the config.
+    trace: Option<ParseTrace>,
 }
 
 impl Parser {
-    pub fn new(topcodes: &Vec<TopCode>) -> Self {
-        let mut tokens = Vec::with_capacity(topcodes.len());
-
-        let mut diameter_sum = 0.0;
-        for topcode in topcodes {
-            if let Some(code) = topcode.code {
-                if let Ok(token_code) = TokenCode::try_from(code) {
-                    diameter_sum += topcode.unit * 8.0;
-                    // Token orientation and y values are flipped so that they follow mathematical
-                    // convention instead of image convention. I may change this in future, but it
-                    // is only an internal representation which should not affect the contract of
-                    // the parser.
-                    let token = Token::new(
-                        token_code,
-                        0.0,
-                        Self::get_angle(-topcode.orientation),
-                        topcode.x,
-                        -topcode.y,
-                    );
-                    tokens.push(token)
-                }
+    pub fn new(topcodes: &[TopCode]) -> Self {
+        Self::with_config(topcodes, ParserConfig::default())
+    }
+
+    pub fn with_config(topcodes: &[TopCode], config: ParserConfig) -> Self {
+        let tokens = topcodes
+            .iter()
+            .enumerate()
+            .filter_map(|(origin, topcode)| {
+                Some((origin, Token::from_topcode(&config.correct(topcode)?)?))
+            })
+            .collect();
+        Self::from_indexed_tokens(tokens, config)
+    }
+
+    pub fn from_tokens(tokens: &[Token], config: ParserConfig) -> Self {
+        Self::from_indexed_tokens(tokens.iter().copied().enumerate().collect(), config)
+    }
+
+    /// Creates a parser for the tokens, each paired with the index of the input it came from.
+    fn from_indexed_tokens(mut tokens: Vec<(usize, Token)>, config: ParserConfig) -> Self {
+        // A single bad detection should not poison the diameter of every other token, so tokens
+        // which cannot be placed are dropped here.
+        tokens.retain(|(_, token)| {
+            let well_formed = token.is_well_formed();
+            if !well_formed {
+                log::debug!("Ignoring malformed token: {:?}", token);
+            }
+            well_formed
+        });
+
+        // The parse should not depend on the order the scanner found the TopCodes in, so tokens
+        // are kept in reading order (top to bottom, then left to right). This also decides which
+        // start token is parsed when there are several.
+        tokens.sort_by(|(_, a), (_, b)| Self::reading_order(a, b));
+        let (origins, mut tokens): (Vec<_>, Vec<_>) = tokens.into_iter().unzip();
+
+        // Individual measurements are noisy, so unless configured otherwise every token shares a
+        // single estimate of the diameter across all TopCodes.
+        let diameters = tokens
+            .iter()
+            .map(|token| token.diameter)
+            .collect::<Vec<_>>();
+        if let Some(diameter) = config.diameter.estimate(&diameters) {
+            for token in &mut tokens {
+                token.diameter = diameter;
             }
         }
 
-        // Averaging out the diameter across all TopCodes to produce more accurate results.
-        //
-        // TODO: Remove outliers using a sample variance calculation to improve this value.
-        let diameter_avg = diameter_sum / tokens.len() as f64;
-        for token in &mut tokens {
-            token.diameter = diameter_avg;
+        // A candidate can be at most the displacement tolerance away from its expected position.
+        let grid = Grid::new(
+            tokens.iter().map(|token| (token.x, token.y)),
+            config.displacement_squared_tolerance.sqrt(),
+        );
+
+        let trace = config.trace.then(ParseTrace::default);
+        let shapes = config.token_shapes();
+        Self {
+            config,
+            grid,
+            linear_scan: false,
+            links: HashMap::new(),
+            parents: vec![None; tokens.len()],
+            fits: vec![None; tokens.len()],
+            nodes: vec![None; tokens.len()],
+            used: vec![false; tokens.len()],
+            visiting: vec![false; tokens.len()],
+            tokens,
+            origins,
+            diagnostics: Vec::new(),
+            spans: SourceMap::default(),
+            confidences: Vec::new(),
+            preferred: HashSet::new(),
+            preference: 0.0,
+            shapes,
+            trace,
         }
+    }
 
-        Self { tokens }
+    /// Finds the candidates for each link by checking every token rather than through the grid,
+    /// with the same results. Only useful as a baseline for the benchmarks.
+    pub(crate) fn with_linear_scan(mut self) -> Self {
+        self.linear_scan = true;
+        self
     }
 
-    fn get_angle(mut angle: f64) -> f64 {
-        while angle > TWO_PI {
-            angle -= TWO_PI;
-        }
-        while angle < 0.0 {
-            angle += TWO_PI;
+    /// Lowers the score of the given links, as (owner, slot, target) indices into the TopCodes
+    /// given to the parser, so that they win contests against slightly better fits.
+    pub(crate) fn prefer_links(
+        mut self,
+        links: impl IntoIterator<Item = Link>,
+        preference: f64,
+    ) -> Self {
+        let mut indices = HashMap::new();
+        for (index, &origin) in self.origins.iter().enumerate() {
+            indices.insert(origin, index);
         }
-        angle
+        self.preferred = links
+            .into_iter()
+            .filter_map(|(owner, slot, target)| {
+                Some((*indices.get(&owner)?, slot, *indices.get(&target)?))
+            })
+            .collect();
+        self.preference = preference;
+        self
     }
 
-    pub fn parse(&self) -> Option<Start> {
-        log::debug!(
-            r#"Starting parser with constants: {{
-  DISPLACEMENT_SQUARED_TOLERANCE: {},
-  ANGLE_TOLERANCE: {}
-}}"#,
-            DISPLACEMENT_SQUARED_TOLERANCE,
-            ANGLE_TOLERANCE,
-        );
+    /// Orders tokens from top to bottom, then left to right. The remaining fields are compared so
+    /// that only identical tokens compare as equal.
+    fn reading_order(a: &Token, b: &Token) -> Ordering {
+        b.y.total_cmp(&a.y)
+            .then(a.x.total_cmp(&b.x))
+            .then(a.code.value().cmp(&b.code.value()))
+            .then(a.orientation.total_cmp(&b.orientation))
+    }
+
+    fn get_angle(angle: f64) -> f64 {
+        angle.rem_euclid(TWO_PI)
+    }
+
+    pub fn parse(self) -> Option<Start> {
+        self.try_parse().ok()
+    }
+
+    pub fn try_parse(self) -> Result<Start, ParseError> {
+        self.parse_report().start.ok_or(ParseError::MissingStart)
+    }
+
+    /// Parses the program along with everything which looked wrong in the scene. Every problem is
+    /// recovered from and reported as a diagnostic, so a report is always produced.
+    pub fn parse_report(self) -> ParseReport {
+        self.parse_linked().0
+    }
 
-        let start_token = self
-            .tokens
+    /// Parses in the same way as [`Self::parse_report`], additionally returning every link as
+    /// (owner, slot, target) indices into the TopCodes given to the parser.
+    pub(crate) fn parse_linked(mut self) -> (ParseReport, Vec<Link>) {
+        let start = self.parse_program();
+        let links = self
+            .links
             .iter()
-            .find(|token| token.code == TokenCode::Start);
+            .map(|(&(owner, slot), &target)| (self.origins[owner], slot, self.origins[target]))
+            .collect();
+        (
+            ParseReport {
+                start,
+                diagnostics: self.diagnostics,
+                spans: self.spans,
+                confidences: self.confidences,
+                trace: self.trace,
+            },
+            links,
+        )
+    }
 
-        self.parse_start(start_token)
+    /// Parses the program in the same way as [`Self::parse_report`], along with every chain of
+    /// flow tokens which is not connected to it.
+    pub fn parse_scene(mut self) -> Scene {
+        let start = self.parse_program();
+        let program = ParseReport {
+            start,
+            diagnostics: std::mem::take(&mut self.diagnostics),
+            spans: std::mem::take(&mut self.spans),
+            confidences: std::mem::take(&mut self.confidences),
+            trace: self.trace.take(),
+        };
+        let fragments = self.parse_fragments();
+        Scene { program, fragments }
     }
 
-    fn parse_start(&self, start_token: Option<&Token>) -> Option<Start> {
-        start_token.map(|token| {
-            log::debug!("Starting with first start token: {:?}", token);
-            let next_token = self.find_adjacent_token(token, None);
-            Start {
-                next: self.parse_flow(next_token),
+    /// Parses a program from every start token, in reading order of the start tokens. Each token
+    /// is linked from at most one slot, so it can only ever be part of one program.
+    pub fn parse_all_programs(mut self) -> Vec<Program> {
+        self.resolve_links();
+        let start_tokens = (0..self.tokens.len())
+            .filter(|&index| self.tokens[index].code == TokenCode::Start)
+            .collect::<Vec<_>>();
+        // Linking is done once for the whole scene, so its diagnostics go to the program of the
+        // token they are about.
+        let links = std::mem::take(&mut self.diagnostics);
+        let mut programs = Vec::with_capacity(start_tokens.len());
+        for index in start_tokens {
+            let start = self.parse_start(Some(index));
+            let spans = std::mem::take(&mut self.spans);
+            let mut diagnostics = links
+                .iter()
+                .filter(|diagnostic| {
+                    spans
+                        .iter()
+                        .any(|(_, span)| span.index == diagnostic.spans[0].index)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            diagnostics.append(&mut self.diagnostics);
+            let confidences = std::mem::take(&mut self.confidences);
+            if let Some(start) = start {
+                programs.push(Program {
+                    start,
+                    diagnostics,
+                    spans,
+                    confidences,
+                });
             }
-        })
+        }
+        programs
     }
 
-    fn parse_flow(&self, current_token: Option<&Token>) -> Option<Flow> {
-        log::debug!("Trying to parse flow from token: {:?}", current_token);
-        current_token.map(|token| match token.code {
-            TokenCode::Shoot
-            | TokenCode::TurnLeft
-            | TokenCode::TurnRight
-            | TokenCode::MoveForwards
-            | TokenCode::MoveBackwards => self.parse_command(token),
-            TokenCode::Blocked => self.parse_conditional(token),
-            TokenCode::While => self.parse_boolean_method(token),
-            TokenCode::Repeat => self.parse_integer_method(token),
-            _ => panic!("Received a flow token that has not been modelled"),
-        })
+    /// Parses every unused chain of flow tokens. A chain starts at a token which no other flow
+    /// token links to, or failing that (when the chain loops back on itself) at its first token
+    /// in reading order.
+    fn parse_fragments(&mut self) -> Vec<Fragment> {
+        let is_unused_flow =
+            |parser: &Self, index: usize| !parser.used[index] && parser.tokens[index].is_flow();
+        let roots = (0..self.tokens.len())
+            .filter(|&index| is_unused_flow(self, index))
+            .filter(|&index| match self.parents[index] {
+                Some(parent) => !self.tokens[parent].is_flow(),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        let mut fragments = Vec::new();
+        for root in roots {
+            fragments.extend(self.parse_fragment(root));
+        }
+        // Whatever is left loops back on itself.
+        while let Some(root) = (0..self.tokens.len()).find(|&index| is_unused_flow(self, index)) {
+            fragments.extend(self.parse_fragment(root));
+        }
+        fragments
+    }
+
+    fn parse_fragment(&mut self, root: usize) -> Option<Fragment> {
+        // The diagnostics and confidences only describe the program, and the fragment has its own
+        // spans.
+        let diagnostics = self.diagnostics.len();
+        let confidences = self.confidences.len();
+        let flow = self.parse_flow(root, Some(root));
+        self.diagnostics.truncate(diagnostics);
+        self.confidences.truncate(confidences);
+        let spans = std::mem::take(&mut self.spans);
+        Some(Fragment { flow: flow?, spans })
     }
 
-    fn parse_command(&self, current_token: &Token) -> Flow {
-        let command = match current_token.code {
-            TokenCode::Shoot => Command::Shoot,
-            TokenCode::TurnLeft => Command::TurnLeft,
-            TokenCode::TurnRight => Command::TurnRight,
-            TokenCode::MoveForwards => Command::MoveForwards,
-            TokenCode::MoveBackwards => Command::MoveBackwards,
-            _ => panic!("Received a non-command token during parse_command"),
+    /// Parses the program from the first start token, reporting anything which is not part of it.
+    fn parse_program(&mut self) -> Option<Start> {
+        log::debug!("Starting parser with config: {:#?}", self.config);
+
+        let mut start_tokens = (0..self.tokens.len())
+            .filter(|&index| self.tokens[index].code == TokenCode::Start)
+            .collect::<Vec<_>>();
+        let start_token = if start_tokens.is_empty() {
+            None
+        } else {
+            Some(start_tokens.remove(0))
         };
-        Flow {
-            kind: FlowKind::Command(command),
-            next: self
-                .parse_flow(self.find_adjacent_token(current_token, None))
-                .map(Box::new),
+
+        if start_token.is_none() {
+            self.report(DiagnosticKind::MissingStart, &[]);
+        }
+        if !start_tokens.is_empty() {
+            self.report(DiagnosticKind::DuplicateStart, &start_tokens);
+            for index in start_tokens {
+                self.used[index] = true;
+            }
+        }
+
+        self.resolve_links();
+        let start = self.parse_start(start_token);
+
+        for index in 0..self.tokens.len() {
+            if !self.used[index] {
+                self.report(DiagnosticKind::UnusedToken, &[index]);
+            }
+        }
+        start
+    }
+
+    /// Records the token as the source of the next node of the AST in pre-order, along with the
+    /// confidence in the link from its parent.
+    fn record_span(&mut self, index: usize) {
+        let span = self.span(index);
+        let child = self.spans.push(span);
+        self.nodes[index] = Some(child);
+        let Some(owner) = self.parents[index] else {
+            return;
+        };
+        if let (Some(parent), Some((slot, fit))) = (self.nodes[owner], self.fits[index]) {
+            self.confidences.push(LinkConfidence {
+                parent,
+                edge: Edge::of(slot, self.tokens[owner].code),
+                child,
+                confidence: fit.confidence(&self.config),
+            });
+        }
+    }
+
+    /// Where the token came from, in image coordinates.
+    fn span(&self, index: usize) -> Span {
+        let token = &self.tokens[index];
+        Span {
+            code: token.code,
+            x: token.x,
+            y: -token.y,
+            orientation: Self::get_angle(-token.orientation),
+            diameter: token.diameter,
+            index: self.origins[index],
+        }
+    }
+
+    fn report(&mut self, kind: DiagnosticKind, indices: &[usize]) {
+        let spans = indices.iter().map(|&index| self.span(index)).collect();
+        self.diagnostics.push(Diagnostic::new(kind, spans));
+    }
+
+    /// Links every slot in the scene to a token. Every candidate of every slot is considered at
+    /// once, from the best fit to the worst, so a token always goes to the slot it fits best and
+    /// a slot which misses out on its best candidate falls back to the next one. Slots which
+    /// missed out, and slots which had several candidates to choose from, are reported.
+    fn resolve_links(&mut self) {
+        let mut proposals = Vec::new();
+        let mut lookups = Vec::new();
+        for owner in 0..self.tokens.len() {
+            for &slot in Slot::of(&self.tokens[owner]) {
+                let Some(lookup) = self.find_tokens(owner, slot) else {
+                    continue;
+                };
+                let candidates = lookup
+                    .candidates
+                    .iter()
+                    .filter(|(_, fit)| fit.within_threshold(&self.config));
+                for (rank, &(target, fit)) in candidates.enumerate() {
+                    proposals.push(Proposal {
+                        owner,
+                        slot,
+                        target,
+                        fit,
+                        rank,
+                    });
+                }
+                if self.trace.is_some() {
+                    lookups.push((owner, slot, lookup));
+                }
+            }
+        }
+        // Tokens are kept in reading order, so ties are broken the same way regardless of the
+        // order of the TopCodes.
+        proposals.sort_by(|a, b| {
+            self.score(a)
+                .total_cmp(&self.score(b))
+                .then(a.owner.cmp(&b.owner))
+                .then(a.slot.cmp(&b.slot))
+                .then(a.target.cmp(&b.target))
+        });
+
+        let mut verdicts = HashMap::new();
+        for proposal in &proposals {
+            let key = (proposal.owner, proposal.slot);
+            let verdict = if self.links.contains_key(&key) {
+                Verdict::SlotTaken
+            } else if self.parents[proposal.target].is_some() {
+                Verdict::LinkedElsewhere
+            } else {
+                self.links.insert(key, proposal.target);
+                self.parents[proposal.target] = Some(proposal.owner);
+                self.fits[proposal.target] = Some((proposal.slot, proposal.fit));
+                Verdict::Linked
+            };
+            if self.trace.is_some() {
+                verdicts.insert((proposal.owner, proposal.slot, proposal.target), verdict);
+            }
+        }
+        self.trace_lookups(lookups, &verdicts);
+
+        for proposal in proposals.iter().filter(|proposal| proposal.rank == 0) {
+            let (owner, target) = (proposal.owner, proposal.target);
+            if self.parents[target] == Some(owner) {
+                continue;
+            }
+            // Losing a token to one of your own ancestors means the layout loops back on itself,
+            // which is more useful to report than the conflict itself.
+            if self.is_ancestor(target, owner) {
+                self.report(DiagnosticKind::Cycle, &[target, owner]);
+            } else if let Some(winner) = self.parents[target] {
+                self.report(DiagnosticKind::Conflict, &[target, winner, owner]);
+            }
         }
+
+        self.report_ambiguities(&proposals);
     }
 
-    fn parse_conditional(&self, current_token: &Token) -> Flow {
-        let conditional_kind = match current_token.code {
-            TokenCode::Blocked => ConditionalKind::Blocked,
-            _ => panic!("Received a non-command token during parse_conditional"),
+    /// Reports every linked slot which had other candidates within the tolerances, unless they
+    /// were linked somewhere else. Those left over could just as well have been meant for the
+    /// slot.
+    fn report_ambiguities(&mut self, proposals: &[Proposal]) {
+        // The proposals are sorted by score, so the linked candidate comes first.
+        let mut competitors = BTreeMap::<_, Vec<_>>::new();
+        for proposal in proposals {
+            let key = (proposal.owner, proposal.slot);
+            let target = proposal.target;
+            if self.links.get(&key) == Some(&target) || self.parents[target].is_none() {
+                competitors
+                    .entry(key)
+                    .or_default()
+                    .push((target, proposal.fit.score(&self.config)));
+            }
+        }
+        for ((owner, _), competitors) in competitors {
+            if competitors.len() < 2 {
+                continue;
+            }
+            let mut spans = vec![self.span(owner)];
+            spans.extend(competitors.iter().map(|&(index, _)| self.span(index)));
+            let scores = competitors.iter().map(|&(_, score)| score).collect();
+            self.diagnostics
+                .push(Diagnostic::new(DiagnosticKind::Ambiguous, spans).with_scores(scores));
+        }
+    }
+
+    fn trace_lookups(
+        &mut self,
+        lookups: Vec<(usize, Slot, Lookup)>,
+        verdicts: &HashMap<Link, Verdict>,
+    ) {
+        let Some(mut trace) = self.trace.take() else {
+            return;
         };
-        let true_token = self.find_true_token(current_token);
-        let false_token = self.find_false_token(current_token);
+        for (owner, slot, lookup) in lookups {
+            let candidates = lookup
+                .candidates
+                .into_iter()
+                .map(|(target, fit)| CandidateTrace {
+                    token: self.span(target),
+                    displacement: fit.displacement_squared.sqrt(),
+                    angle_error: fit.angle,
+                    verdict: verdicts
+                        .get(&(owner, slot, target))
+                        .copied()
+                        .unwrap_or(Verdict::OutOfTolerance),
+                })
+                .collect();
+            trace.lookups.push(LookupTrace {
+                owner: self.span(owner),
+                slot,
+                expected_x: lookup.x,
+                expected_y: -lookup.y,
+                expected_angle: Self::get_angle(-lookup.angle),
+                candidates,
+            });
+        }
+        self.trace = Some(trace);
+    }
+
+    /// The score of the proposal, lowered if it is one of the preferred links.
+    fn score(&self, proposal: &Proposal) -> f64 {
+        let score = proposal.fit.score(&self.config);
+        if self
+            .preferred
+            .contains(&(proposal.owner, proposal.slot, proposal.target))
+        {
+            score - self.preference
+        } else {
+            score
+        }
+    }
+
+    /// Whether the ancestor is reachable by following the parents of the given token.
+    fn is_ancestor(&self, ancestor: usize, mut index: usize) -> bool {
+        // Parents may form a loop when nothing outside of the loop links to it, so this is
+        // bounded by the number of tokens.
+        for _ in 0..self.tokens.len() {
+            if index == ancestor {
+                return true;
+            }
+            match self.parents[index] {
+                Some(parent) => index = parent,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn linked(&self, owner: usize, slot: Slot) -> Option<usize> {
+        self.links.get(&(owner, slot)).copied()
+    }
+
+    fn parse_start(&mut self, start_token: Option<usize>) -> Option<Start> {
+        let index = start_token?;
+        log::debug!("Starting with first start token: {:?}", self.tokens[index]);
+        self.used[index] = true;
+        self.record_span(index);
+        let next_token = self.linked(index, Slot::Adjacent);
+        Some(Start {
+            next: self.parse_flow(index, next_token),
+        })
+    }
+
+    /// Parses the flow linked from the parent token. The walk keeps its own stack of steps rather
+    /// than recursing, so that a long line of tokens cannot overflow the stack.
+    fn parse_flow(&mut self, parent: usize, first_token: Option<usize>) -> Option<Flow> {
+        let mut steps = vec![Step::Link {
+            parent,
+            token: first_token,
+        }];
+        // The flows parsed from each link which have not been attached to their parent yet.
+        let mut parsed: Vec<Option<Box<Flow>>> = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Link { parent, token } => {
+                    let Some(index) = token else {
+                        parsed.push(None);
+                        continue;
+                    };
+                    // Physically looped layouts (e.g. a ring of commands) would otherwise be
+                    // walked forever, so a link back to a token which is still being parsed is
+                    // reported and treated as the end of the chain.
+                    if self.visiting[index] {
+                        log::debug!("Cutting cycle at token: {:?}", self.tokens[index]);
+                        self.report(DiagnosticKind::Cycle, &[index, parent]);
+                        parsed.push(None);
+                        continue;
+                    }
+                    // Slots only accept flow tokens (see `Slot::accepts`), so this only guards
+                    // the walk.
+                    if !self.parse_node(index, &mut steps) {
+                        parsed.push(None);
+                    }
+                }
+                Step::CheckBody(method) => {
+                    if let Some(None) = parsed.last() {
+                        self.report(DiagnosticKind::MissingBody, &[method]);
+                    }
+                }
+                Step::Report(kind, index) => self.report(kind, &[index]),
+                Step::Finish { index, mut kind } => {
+                    let next = parsed.pop().flatten();
+                    if let Some(branch) = kind.branch_mut() {
+                        *branch = parsed.pop().flatten();
+                    }
+                    self.visiting[index] = false;
+                    parsed.push(Some(Box::new(Flow { kind, next })));
+                }
+            }
+        }
+        parsed.pop().flatten().map(|flow| *flow)
+    }
+
+    /// Starts parsing the flow token at the given index, scheduling the steps which parse the
+    /// rest of it. The steps are pushed in reverse, so the branch is parsed before the next flow.
+    /// Returns false, parsing nothing, if the token is not a flow token.
+    fn parse_node(&mut self, index: usize, steps: &mut Vec<Step>) -> bool {
+        log::debug!("Trying to parse flow from token: {:?}", self.tokens[index]);
+        let Some(kind) = Self::flow_kind(&self.tokens[index]) else {
+            return false;
+        };
+        self.used[index] = true;
+        self.record_span(index);
+        self.visiting[index] = true;
+        match kind {
+            FlowKind::Command(_) => self.parse_command(index, kind, steps),
+            FlowKind::Conditional(_) => self.parse_conditional(index, kind, steps),
+            FlowKind::BooleanMethod(method) => self.parse_boolean_method(index, method, steps),
+            FlowKind::IntegerMethod(method) => self.parse_integer_method(index, method, steps),
+        }
+        true
+    }
+
+    /// The node for a flow token, before anything linked to it has been parsed.
+    fn flow_kind(token: &Token) -> Option<FlowKind> {
+        let command = |command| Some(FlowKind::Command(command));
+        match token.code {
+            TokenCode::Shoot => command(Command::Shoot),
+            TokenCode::TurnLeft => command(Command::TurnLeft),
+            TokenCode::TurnRight => command(Command::TurnRight),
+            TokenCode::MoveForwards => command(Command::MoveForwards),
+            TokenCode::MoveBackwards => command(Command::MoveBackwards),
+            TokenCode::Blocked => Some(FlowKind::Conditional(Conditional {
+                kind: ConditionalKind::Blocked,
+                alternate: None,
+            })),
+            TokenCode::While => Some(FlowKind::BooleanMethod(BooleanMethod {
+                kind: BooleanMethodKind::While,
+                body: None,
+                condition: None,
+            })),
+            TokenCode::Repeat => Some(FlowKind::IntegerMethod(IntegerMethod {
+                kind: IntegerMethodKind::Repeat,
+                body: None,
+                value: None,
+            })),
+            _ => None,
+        }
+    }
+
+    fn parse_command(&mut self, index: usize, kind: FlowKind, steps: &mut Vec<Step>) {
+        steps.push(Step::Finish { index, kind });
+        steps.push(Step::Link {
+            parent: index,
+            token: self.linked(index, Slot::Adjacent),
+        });
+    }
+
+    fn parse_conditional(&mut self, index: usize, kind: FlowKind, steps: &mut Vec<Step>) {
+        let true_token = self.linked(index, Slot::True);
+        let false_token = self.linked(index, Slot::False);
         log::debug!(
             "parse_conditional {{ true_token: {:?}, false_token: {:?} }}",
-            true_token,
-            false_token
+            true_token.map(|index| self.tokens[index]),
+            false_token.map(|index| self.tokens[index])
         );
-        Flow {
-            kind: FlowKind::Conditional(Conditional {
-                kind: conditional_kind,
-                alternate: self.parse_flow(false_token).map(Box::new),
-            }),
-            next: self.parse_flow(true_token).map(Box::new),
+        if true_token.is_none() {
+            self.report(DiagnosticKind::MissingTrueBranch, &[index]);
         }
+        steps.push(Step::Finish { index, kind });
+        steps.push(Step::Link {
+            parent: index,
+            token: true_token,
+        });
+        steps.push(Step::Link {
+            parent: index,
+            token: false_token,
+        });
     }
 
-    fn parse_boolean_method(&self, current_token: &Token) -> Flow {
-        let boolean_method_kind = match current_token.code {
-            TokenCode::While => BooleanMethodKind::While,
-            _ => panic!(
-                "Received a token which was not a boolean method token in parse_boolean_method"
-            ),
-        };
-        Flow {
-            kind: FlowKind::BooleanMethod(BooleanMethod {
-                kind: boolean_method_kind,
-                body: self
-                    .parse_flow(self.find_method_body_token(current_token))
-                    .map(Box::new),
-                condition: self.parse_condition(
-                    self.find_method_parameter_token(current_token, &Token::is_condition),
-                ),
-            }),
-            next: self
-                .parse_flow(self.find_adjacent_token(current_token, None))
-                .map(Box::new),
-        }
+    fn parse_boolean_method(
+        &mut self,
+        index: usize,
+        mut method: BooleanMethod,
+        steps: &mut Vec<Step>,
+    ) {
+        let parameter_token = self.linked(index, Slot::Parameter);
+        method.condition = self.parse_parameter(parameter_token, Self::parse_condition);
+        let has_parameter = method.condition.is_some();
+        self.schedule_method(index, FlowKind::BooleanMethod(method), has_parameter, steps);
     }
 
-    fn parse_condition(&self, candidate: Option<&Token>) -> Option<Condition> {
-        candidate.and_then(|token| match token.code {
+    fn parse_condition(token: &Token) -> Option<Condition> {
+        match token.code {
             TokenCode::IsBlocked => Some(Condition::IsBlocked),
             TokenCode::IsPathClear => Some(Condition::IsPathClear),
             _ => None,
-        })
+        }
     }
 
-    fn parse_integer_method(&self, current_token: &Token) -> Flow {
-        let integer_method_kind = match current_token.code {
-            TokenCode::Repeat => IntegerMethodKind::Repeat,
-            _ => panic!(
-                "Received a token which was not an integer method token in parse_integer_method"
-            ),
-        };
-        Flow {
-            kind: FlowKind::IntegerMethod(IntegerMethod {
-                kind: integer_method_kind,
-                body: self
-                    .parse_flow(self.find_method_body_token(current_token))
-                    .map(Box::new),
-                value: self
-                    .parse_value(self.find_method_parameter_token(current_token, &Token::is_value)),
-            }),
-            next: self
-                .parse_flow(self.find_adjacent_token(current_token, None))
-                .map(Box::new),
-        }
+    fn parse_integer_method(
+        &mut self,
+        index: usize,
+        mut method: IntegerMethod,
+        steps: &mut Vec<Step>,
+    ) {
+        let parameter_token = self.linked(index, Slot::Parameter);
+        method.value = self.parse_parameter(parameter_token, Self::parse_value);
+        let has_parameter = method.value.is_some();
+        self.schedule_method(index, FlowKind::IntegerMethod(method), has_parameter, steps);
     }
 
-    fn parse_value(&self, candidate: Option<&Token>) -> Option<Value> {
-        candidate.and_then(|token| match token.code {
+    fn parse_value(token: &Token) -> Option<Value> {
+        match token.code {
             TokenCode::Value1 => Some(Value::One),
             TokenCode::Value2 => Some(Value::Two),
             TokenCode::Value3 => Some(Value::Three),
@@ -257,150 +852,181 @@ impl Parser {
             TokenCode::Value8 => Some(Value::Eight),
             TokenCode::ValueInfinite => Some(Value::Infinity),
             _ => None,
-        })
+        }
     }
 
-    /// Finds the method parameter token for the given method type. The predicate is used to ensure
-    /// the input type is as expected (condition vs value).
-    fn find_method_parameter_token(
+    /// Schedules the body and next flow of a method whose parameter has already been parsed. The
+    /// parameter comes before the body in pre-order, so it is parsed first to keep the spans in
+    /// order, but only reported once the body has been.
+    fn schedule_method(
         &self,
-        token: &Token,
-        predicate: &impl Fn(&Token) -> bool,
-    ) -> Option<&Token> {
-        log::debug!("Trying to parse parameter from token: {:?}", token);
-        let ratio = token.ratio(TOPCODE_DIAMETER);
-        let distance = ratio * -TOKEN_SIZE;
-        let x = token.x + distance * -token.orientation.sin();
-        let y = token.y + distance * token.orientation.cos();
-        for candidate in &self.tokens {
-            if token == candidate || !predicate(candidate) {
-                continue;
-            }
-
-            if Self::within_threshold(candidate, x, y, token.orientation) {
-                return Some(candidate);
-            }
+        index: usize,
+        kind: FlowKind,
+        has_parameter: bool,
+        steps: &mut Vec<Step>,
+    ) {
+        steps.push(Step::Finish { index, kind });
+        steps.push(Step::Link {
+            parent: index,
+            token: self.linked(index, Slot::Adjacent),
+        });
+        if !has_parameter {
+            steps.push(Step::Report(DiagnosticKind::MissingParameter, index));
         }
-        None
+        steps.push(Step::CheckBody(index));
+        steps.push(Step::Link {
+            parent: index,
+            token: self.linked(index, Slot::Body),
+        });
     }
 
-    fn find_method_body_token(&self, token: &Token) -> Option<&Token> {
-        let ratio = token.ratio(TOPCODE_DIAMETER);
-        let angle = token.orientation + PI / 2.0;
-        let x_delta = -(TOPCODE_CENTER_X - TOPCODE_CENTER_Y);
-        let y_delta = TOKEN_SIZE + x_delta;
-        let cos_angle = token.orientation.cos();
-        let sin_angle = token.orientation.sin();
-        let x = token.x + (x_delta * cos_angle - y_delta * sin_angle) * ratio;
-        let y = token.y + (x_delta * sin_angle + y_delta * cos_angle) * ratio;
-
-        for candidate in &self.tokens {
-            if token == candidate || !candidate.is_flow() {
-                continue;
-            }
-
-            if Self::within_threshold(candidate, x, y, angle) {
-                return Some(candidate);
-            }
+    /// Parses the parameter of a method from the candidate token, if it is the right kind.
+    fn parse_parameter<T>(
+        &mut self,
+        candidate: Option<usize>,
+        parse: impl Fn(&Token) -> Option<T>,
+    ) -> Option<T> {
+        let index = candidate?;
+        let parameter = parse(&self.tokens[index]);
+        if parameter.is_some() {
+            self.used[index] = true;
+            self.record_span(index);
         }
-
-        None
+        parameter
     }
 
-    /// Given a flow token, find the token which is adjacent to it.
-    fn find_adjacent_token(&self, token: &Token, parent: Option<&Token>) -> Option<&Token> {
-        let ratio = token.ratio(TOPCODE_DIAMETER);
-        let distance = ratio * TOKEN_SIZE;
-        let x = token.x + (distance * token.orientation.cos());
-        let y = token.y + (distance * token.orientation.sin());
+    /// Finds the candidates for the given slot of the owner, best fit first, if its shape has a
+    /// connection for the slot.
+    fn find_tokens(&self, owner: usize, slot: Slot) -> Option<Lookup> {
+        let token = &self.tokens[owner];
+        let connection = self.shapes.connection(token.code, slot)?;
+        let (x, y, angle) = connection.target(token, &self.config);
+        let predicate = |candidate: &Token| slot.accepts(token, candidate);
+        Some(self.find_candidates(owner, x, y, angle, &predicate))
+    }
 
-        for candidate in &self.tokens {
-            if candidate == token || !candidate.is_flow() {
+    /// Finds every candidate near the expected position, other than the owner, which matches the
+    /// predicate. The closest candidate comes first.
+    fn find_candidates(
+        &self,
+        owner: usize,
+        x: f64,
+        y: f64,
+        angle: f64,
+        predicate: &impl Fn(&Token) -> bool,
+    ) -> Lookup {
+        let nearby = if self.linear_scan {
+            (0..self.tokens.len())
+                .filter(|&index| {
+                    let token = &self.tokens[index];
+                    self.grid.is_near((token.x, token.y), x, y)
+                })
+                .collect()
+        } else {
+            self.grid.near(x, y)
+        };
+        let mut candidates = Vec::new();
+        for index in nearby {
+            let candidate = &self.tokens[index];
+            if index == owner || !predicate(candidate) {
                 continue;
             }
 
-            if let Some(parent) = parent {
-                if parent == candidate {
-                    continue;
-                }
-            }
-
-            if Self::within_threshold(candidate, x, y, token.orientation) {
-                return Some(candidate);
-            }
+            candidates.push((index, self.fit(candidate, x, y, angle)));
+        }
+        // The sort is stable, so equally good candidates stay in reading order.
+        candidates
+            .sort_by(|(_, a), (_, b)| a.score(&self.config).total_cmp(&b.score(&self.config)));
+        Lookup {
+            x,
+            y,
+            angle,
+            candidates,
         }
-
-        None
     }
 
-    fn within_threshold(candidate: &Token, x: f64, y: f64, angle: f64) -> bool {
-        let delta_displacement_squared = (candidate.x - x).powi(2) + (candidate.y - y).powi(2);
-        let mut delta_angle = (candidate.orientation - angle) % TWO_PI;
-        if delta_angle < 0.0 {
-            delta_angle += TWO_PI;
-        }
-        delta_angle = f64::min(delta_angle, TWO_PI - delta_angle);
+    fn fit(&self, candidate: &Token, x: f64, y: f64, angle: f64) -> Fit {
+        let fit = Fit::new(candidate, x, y, angle);
         log::debug!(
             "within_threshold: {{\n  candidate: {:?}\n  expected_x: {}\n  expected_y: {}\n  expected_angle: {}\n  delta_displacement_squared: {}\n  delta_angle: {}\n  evaluation: {}\n}}",
             candidate,
             x,
             y,
             angle,
-            delta_displacement_squared,
-            delta_angle,
-            delta_displacement_squared < DISPLACEMENT_SQUARED_TOLERANCE && delta_angle < ANGLE_TOLERANCE
-        );
-        delta_displacement_squared <= DISPLACEMENT_SQUARED_TOLERANCE
-            && delta_angle <= ANGLE_TOLERANCE
-    }
-
-    fn find_true_token(&self, token: &Token) -> Option<&Token> {
-        log::debug!("Trying to find true token...");
-        let ratio = token.ratio(TOPCODE_DIAMETER);
-        let angle = token.orientation + PI / 4.0;
-        let cos_angle = token.orientation.cos();
-        let sin_angle = token.orientation.sin();
-        let x = token.x + (*TRUE_TOKEN_X * cos_angle - *TRUE_TOKEN_Y * sin_angle) * ratio;
-        let y = token.y + (*TRUE_TOKEN_X * sin_angle + *TRUE_TOKEN_Y * cos_angle) * ratio;
-        // Create an artificial to find the next flow token
-        let pseudo_token = Token::new(TokenCode::Undefined, token.diameter, angle, x, y);
-        log::debug!("Pseudo true token: {:?}", pseudo_token);
-        self.find_adjacent_token(&pseudo_token, Some(token))
-    }
-
-    fn find_false_token(&self, token: &Token) -> Option<&Token> {
-        log::debug!("Trying to find false token...");
-        let ratio = token.ratio(TOPCODE_DIAMETER);
-        let angle = token.orientation - PI / 4.0;
-        let cos_angle = token.orientation.cos();
-        let sin_angle = token.orientation.sin();
-        let x = token.x + (*FALSE_TOKEN_X * cos_angle - *FALSE_TOKEN_Y * sin_angle) * ratio;
-        let y = token.y + (*FALSE_TOKEN_X * sin_angle + *FALSE_TOKEN_Y * cos_angle) * ratio;
-        // Create an artificial to find the next flow token
-        let pseudo_token = Token::new(TokenCode::Undefined, token.diameter, angle, x, y);
-        log::debug!("Pseudo false token: {:?}", pseudo_token);
-        self.find_adjacent_token(&pseudo_token, Some(token))
+            fit.displacement_squared,
+            fit.angle,
+            fit.within_threshold(&self.config)
+        );
+        fit
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{flow, start, DiameterEstimate, Homography, LensDistortion, NodeId};
+    use enum_iterator::all;
+    use proptest::prelude::*;
 
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    fn complex_topcodes() -> Vec<TopCode> {
+        vec![
+            TopCode::mock(61, 12.375, -5.244, 237.5, 165.166),
+            TopCode::mock(79, 14.306, -5.244, 336.333, 363.5),
+            TopCode::mock(31, 12.15, -5.244, 431.0, 562.0),
+            TopCode::mock(47, 12.262, -2.827, 937.833, 377.5),
+            TopCode::mock(91, 12.943, -1.232, 1140.5, 436.0),
+            TopCode::mock(115, 14.175, -1.280, 1345.5, 519.5),
+            TopCode::mock(59, 12.706, -1.280, 1052.5, 641.666),
+            TopCode::mock(55, 13.912, -5.920, 801.5, 754.833),
+            TopCode::mock(91, 13.912, -5.969, 999.0, 838.0),
+            TopCode::mock(167, 12.468, -4.422, 134.5, 912.833),
+            TopCode::mock(87, 12.15, -5.969, 1201.666, 916.0),
+            TopCode::mock(155, 13.125, -4.422, 336.833, 979.5),
+            TopCode::mock(47, 12.35, -5.969, 538.0, 1031.5),
+            TopCode::mock(117, 13.25, -5.969, 917.666, 1035.5),
+            TopCode::mock(87, 11.812, -4.47, 275.33, 1177.33),
+        ]
+    }
+
+    /// A start token followed by a ring of command tokens, each one angled slightly from the last
+    /// so that the final token points back at the first.
+    fn ring_topcodes(count: usize) -> Vec<TopCode> {
+        let token_size = ParserConfig::default().token_size;
+        let step = TWO_PI / count as f64;
+        let radius = token_size / (2.0 * (step / 2.0).sin());
+        let position = |angle: f64| (radius * angle.cos(), radius * angle.sin());
+        let mut topcodes = (0..count)
+            .map(|i| {
+                let (x, y) = position(i as f64 * step);
+                let orientation = i as f64 * step + PI / 2.0;
+                TopCode::mock(TokenCode::Shoot.value(), 6.0, -orientation, x, -y)
+            })
+            .collect::<Vec<_>>();
+        let (x, y) = position(0.0);
+        topcodes.push(TopCode::mock(
+            TokenCode::Start.value(),
+            6.0,
+            -PI / 2.0,
+            x,
+            -(y - token_size),
+        ));
+        topcodes
+    }
+
     #[test]
     fn it_can_parse_a_start_token() {
-        let parser = Parser::new(&vec![TopCode::new(TokenCode::Start.value())]);
+        let parser = Parser::new(&[TopCode::new(TokenCode::Start.value())]);
         let result = parser.parse();
         assert_eq!(Some(Start { next: None }), result);
     }
 
     #[test]
     fn it_can_parse_a_trivial_flow() {
-        let parser = Parser::new(&vec![
+        let parser = Parser::new(&[
             TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
             TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 0.0),
         ]);
@@ -421,25 +1047,7 @@ mod tests {
         init();
         // This is essentially a fuzz test as there are a few missing unit tests above. This covers
         // quite a complex (although semantically nonsense) Tangibl AST.
-        let topcodes = vec![
-            TopCode::mock(61, 12.375, -5.244, 237.5, 165.166),
-            TopCode::mock(79, 14.306, -5.244, 336.333, 363.5),
-            TopCode::mock(31, 12.15, -5.244, 431.0, 562.0),
-            TopCode::mock(47, 12.262, -2.827, 937.833, 377.5),
-            TopCode::mock(91, 12.943, -1.232, 1140.5, 436.0),
-            TopCode::mock(115, 14.175, -1.280, 1345.5, 519.5),
-            TopCode::mock(59, 12.706, -1.280, 1052.5, 641.666),
-            TopCode::mock(55, 13.912, -5.920, 801.5, 754.833),
-            TopCode::mock(91, 13.912, -5.969, 999.0, 838.0),
-            TopCode::mock(167, 12.468, -4.422, 134.5, 912.833),
-            TopCode::mock(87, 12.15, -5.969, 1201.666, 916.0),
-            TopCode::mock(155, 13.125, -4.422, 336.833, 979.5),
-            TopCode::mock(47, 12.35, -5.969, 538.0, 1031.5),
-            TopCode::mock(117, 13.25, -5.969, 917.666, 1035.5),
-            TopCode::mock(87, 11.812, -4.47, 275.33, 1177.33),
-        ];
-
-        let parser = Parser::new(&topcodes);
+        let parser = Parser::new(&complex_topcodes());
 
         assert_eq!(
             Some(Start {
@@ -496,4 +1104,871 @@ mod tests {
             parser.parse()
         );
     }
+
+    #[test]
+    fn it_reports_nothing_for_a_complete_program() {
+        let report = Parser::new(&complex_topcodes()).parse_report();
+        assert!(report.is_clean(), "{:?}", report.diagnostics);
+    }
+
+    #[test]
+    fn it_reports_a_missing_start_token() {
+        let report = Parser::new(&[TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 0.0, 0.0)])
+            .parse_report();
+        assert_eq!(None, report.start);
+        let kinds = report
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![DiagnosticKind::MissingStart, DiagnosticKind::UnusedToken],
+            kinds
+        );
+    }
+
+    #[test]
+    fn it_reports_duplicate_start_and_unused_tokens() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 500.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 500.0, 0.0),
+        ])
+        .parse_report();
+        assert_eq!(Some(Start { next: None }), report.start);
+        let duplicates = report
+            .diagnostics_of(DiagnosticKind::DuplicateStart)
+            .collect::<Vec<_>>();
+        assert_eq!(1, duplicates.len());
+        // Diagnostics are in the coordinates of the image, like the spans of the AST.
+        assert_eq!(500.0, duplicates[0].spans[0].y);
+        assert_eq!(1, duplicates[0].spans[0].index);
+        let unused = report
+            .diagnostics_of(DiagnosticKind::UnusedToken)
+            .collect::<Vec<_>>();
+        assert_eq!(1, unused.len());
+        assert_eq!(TokenCode::Shoot, unused[0].spans[0].code);
+        assert_eq!("T003", unused[0].code());
+    }
+
+    #[test]
+    fn it_reports_an_incomplete_method() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+        ])
+        .parse_report();
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::IntegerMethod(IntegerMethod {
+                    kind: IntegerMethodKind::Repeat,
+                    body: None,
+                    value: None,
+                })))
+            }),
+            report.start
+        );
+        let kinds = report
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.kind, diagnostic.spans[0].code))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (DiagnosticKind::MissingBody, TokenCode::Repeat),
+                (DiagnosticKind::MissingParameter, TokenCode::Repeat),
+            ],
+            kinds
+        );
+    }
+
+    #[test]
+    fn it_reports_a_conditional_without_a_true_branch() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Blocked.value(), 6.0, 0.0, 100.0, 0.0),
+        ])
+        .parse_report();
+        assert_eq!(1, report.diagnostics.len());
+        assert_eq!(
+            DiagnosticKind::MissingTrueBranch,
+            report.diagnostics[0].kind
+        );
+    }
+
+    #[test]
+    fn it_parses_tokens_the_same_as_topcodes() {
+        let topcodes = complex_topcodes();
+        let tokens = crate::tokenize(&topcodes);
+        assert_eq!(topcodes.len(), tokens.len());
+        assert_eq!(
+            Parser::new(&topcodes).parse_report(),
+            Parser::from_tokens(&tokens, ParserConfig::default()).parse_report()
+        );
+    }
+
+    #[test]
+    fn it_parses_a_long_chain_without_overflowing_the_stack() {
+        let mut topcodes = vec![TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0)];
+        let mut expected = start();
+        for i in 1..=100_000 {
+            topcodes.push(TopCode::mock(
+                TokenCode::Shoot.value(),
+                6.0,
+                0.0,
+                i as f64 * 100.0,
+                0.0,
+            ));
+            expected.with_command(Command::Shoot);
+        }
+        let report = Parser::new(&topcodes).parse_report();
+        assert!(report.is_clean());
+        // Not assert_eq, as printing the AST on failure would recurse.
+        assert!(report.start == Some(expected.build()));
+    }
+
+    #[test]
+    fn it_parses_the_fragments_which_are_not_connected_to_the_program() {
+        let scene = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 0.0),
+            // The chain is broken here.
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 400.0, 0.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 500.0, 0.0),
+            TopCode::mock(TokenCode::Value2.value(), 6.0, 0.0, 545.0, 100.0),
+            // A second program, which is a fragment of the first.
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 500.0),
+            TopCode::mock(TokenCode::MoveForwards.value(), 6.0, 0.0, 100.0, 500.0),
+        ])
+        .parse_scene();
+        assert_eq!(
+            Some(Start {
+                next: flow().with_command(Command::Shoot).build()
+            }),
+            scene.program.start
+        );
+        assert_eq!(
+            4,
+            scene
+                .program
+                .diagnostics_of(DiagnosticKind::UnusedToken)
+                .count()
+        );
+        let fragments = scene
+            .fragments
+            .iter()
+            .map(|fragment| fragment.flow.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                flow()
+                    .with_command(Command::TurnLeft)
+                    .with_integer_method(IntegerMethodKind::Repeat, Some(Value::Two), None)
+                    .build()
+                    .unwrap(),
+                Flow::new(FlowKind::Command(Command::MoveForwards)),
+            ],
+            fragments
+        );
+        assert_eq!(3, scene.fragments[0].spans.len());
+        assert_eq!(
+            TokenCode::TurnLeft,
+            scene.fragments[0].spans.get(NodeId(0)).unwrap().code
+        );
+    }
+
+    #[test]
+    fn it_parses_a_program_from_every_start_token() {
+        // The shoot token is slightly closer to the end of the bottom program.
+        let topcodes = vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 100.0),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 100.0, 100.0),
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::TurnRight.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 200.0, 55.0),
+        ];
+        let programs = Parser::new(&topcodes).parse_all_programs();
+        let starts = programs
+            .iter()
+            .map(|program| program.start.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Start {
+                    next: flow().with_command(Command::TurnRight).build()
+                },
+                Start {
+                    next: flow()
+                        .with_command(Command::TurnLeft)
+                        .with_command(Command::Shoot)
+                        .build()
+                },
+            ],
+            starts
+        );
+        assert_eq!(2, programs[0].spans.start().unwrap().index);
+        // Each program reports its own problems and links, and the contested shoot token is only
+        // reported by the program it went to.
+        assert!(programs[0].diagnostics.is_empty());
+        assert_eq!(1.0, programs[0].confidence());
+        let kinds = programs[1]
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.kind, diagnostic.spans[0].index))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(DiagnosticKind::Conflict, 4)], kinds);
+        assert_eq!(2, programs[1].confidences.len());
+        assert!(programs[1].confidence() < 1.0);
+
+        let mut reversed = topcodes.clone();
+        reversed.reverse();
+        assert_eq!(
+            starts,
+            Parser::new(&reversed)
+                .parse_all_programs()
+                .into_iter()
+                .map(|program| program.start)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_parses_a_looped_fragment() {
+        let mut topcodes = ring_topcodes(12);
+        topcodes.pop();
+        let scene = Parser::new(&topcodes).parse_scene();
+        assert_eq!(None, scene.program.start);
+        assert_eq!(1, scene.fragments.len());
+        assert_eq!(12, scene.fragments[0].spans.len());
+    }
+
+    #[test]
+    fn it_ignores_malformed_topcodes() {
+        let mut nan = TopCode::mock(TokenCode::Shoot.value(), f64::NAN, 0.0, 100.0, 0.0);
+        nan.x = f64::NAN;
+        let result = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            nan,
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, f64::INFINITY, 100.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 0.0),
+        ])
+        .try_parse();
+        assert_eq!(
+            Ok(Start {
+                next: Some(Flow::new(FlowKind::Command(Command::Shoot)))
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn it_returns_an_error_without_a_start_token() {
+        assert_eq!(Err(ParseError::MissingStart), Parser::new(&[]).try_parse());
+    }
+
+    #[test]
+    fn it_cuts_a_ring_of_tokens_at_the_repeated_token() {
+        let report = Parser::new(&ring_topcodes(12)).parse_report();
+        let mut expected = flow();
+        for _ in 0..12 {
+            expected.with_command(Command::Shoot);
+        }
+        assert_eq!(
+            Some(Start {
+                next: expected.build()
+            }),
+            report.start
+        );
+        let cycles = report
+            .diagnostics_of(DiagnosticKind::Cycle)
+            .collect::<Vec<_>>();
+        assert_eq!(1, cycles.len());
+        // The first token of the ring is repeated, and the last token links back to it.
+        let first = ring_topcodes(12)[0];
+        assert_eq!(
+            (first.x, first.y),
+            (cycles[0].spans[0].x, cycles[0].spans[0].y)
+        );
+        assert_ne!(cycles[0].spans[0], cycles[0].spans[1]);
+    }
+
+    #[test]
+    fn it_cuts_a_cycle_between_overlapping_tokens() {
+        // Small tokens detected twice in the same place link to each other.
+        let result = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 2.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 2.0, 0.0, 33.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 2.0, 0.0, 34.0, 0.0),
+        ])
+        .parse_report();
+        assert_eq!(
+            Some(Start {
+                next: flow()
+                    .with_command(Command::Shoot)
+                    .with_command(Command::Shoot)
+                    .build()
+            }),
+            result.start
+        );
+        assert_eq!(1, result.diagnostics_of(DiagnosticKind::Cycle).count());
+    }
+
+    fn topcode() -> impl Strategy<Value = TopCode> {
+        let token_codes = all::<TokenCode>()
+            .map(|code| code.value())
+            .collect::<Vec<_>>();
+        let code = prop_oneof![
+            4 => prop::sample::select(token_codes).prop_map(Some),
+            1 => any::<u32>().prop_map(Some),
+            1 => Just(None),
+        ];
+        let number = |range: std::ops::Range<f64>| {
+            prop_oneof![
+                8 => range,
+                1 => any::<f64>(),
+                1 => prop::sample::select(vec![0.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY]),
+            ]
+        };
+        (
+            code,
+            number(0.0..20.0),
+            number(-TWO_PI..TWO_PI),
+            number(0.0..500.0),
+            number(0.0..500.0),
+        )
+            .prop_map(|(code, unit, orientation, x, y)| {
+                let mut topcode = TopCode::mock(0, unit, orientation, x, y);
+                topcode.code = code;
+                topcode
+            })
+    }
+
+    /// Two candidates for the token after start, where the first one is further away.
+    fn contested_topcodes() -> Vec<TopCode> {
+        vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 130.0, 0.0),
+            TopCode::mock(TokenCode::MoveForwards.value(), 6.0, 0.0, 105.0, 0.0),
+        ]
+    }
+
+    #[test]
+    fn it_reports_several_tokens_fitting_one_slot() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 5.0),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 100.0, -15.0),
+        ])
+        .parse_report();
+        assert_eq!(
+            Some(Start {
+                next: flow().with_command(Command::Shoot).build()
+            }),
+            report.start
+        );
+        let ambiguities = report
+            .diagnostics_of(DiagnosticKind::Ambiguous)
+            .collect::<Vec<_>>();
+        assert_eq!(1, ambiguities.len());
+        assert_eq!(
+            vec![TokenCode::Start, TokenCode::Shoot, TokenCode::TurnLeft],
+            ambiguities[0]
+                .spans
+                .iter()
+                .map(|span| span.code)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("T009", ambiguities[0].code());
+        let scores = &ambiguities[0].scores;
+        assert_eq!(2, scores.len());
+        assert!(0.0 < scores[0] && scores[0] < scores[1] && scores[1] <= 2.0);
+    }
+
+    #[test]
+    fn it_picks_the_closest_candidate() {
+        let result = Parser::new(&contested_topcodes()).parse();
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::Command(Command::MoveForwards)))
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn it_uses_the_configured_tolerances() {
+        // Too far from the start token for the default tolerance.
+        let topcodes = vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.3, 180.0, 0.0),
+        ];
+        assert_eq!(Some(Start { next: None }), Parser::new(&topcodes).parse());
+
+        let config = ParserConfig {
+            displacement_squared_tolerance: 100.0 * 100.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::Command(Command::Shoot)))
+            }),
+            Parser::with_config(&topcodes, config.clone()).parse()
+        );
+
+        let config = ParserConfig {
+            angle_tolerance: 0.2,
+            ..config
+        };
+        assert_eq!(
+            Some(Start { next: None }),
+            Parser::with_config(&topcodes, config).parse()
+        );
+    }
+
+    #[test]
+    fn it_uses_the_configured_token_geometry() {
+        let topcodes = vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 200.0, 0.0),
+        ];
+        assert_eq!(Some(Start { next: None }), Parser::new(&topcodes).parse());
+
+        let config = ParserConfig {
+            token_size: 200.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::Command(Command::Shoot)))
+            }),
+            Parser::with_config(&topcodes, config).parse()
+        );
+    }
+
+    #[test]
+    fn it_uses_the_configured_token_shapes() {
+        // A start token which connects below itself, rather than to its right.
+        let topcodes = vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 0.0, 100.0),
+        ];
+        let mut shapes = ParserConfig::default().token_shapes();
+        shapes.extend(
+            TokenShapes::from_json(
+                r#"{
+                    "Start": { "connections": [{ "slot": "adjacent", "x": 0.0, "y": -100.0, "angle": 0.0 }] }
+                }"#,
+            )
+            .unwrap(),
+        );
+        let config = ParserConfig {
+            shapes: Some(shapes),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::Command(Command::TurnLeft)))
+            }),
+            Parser::with_config(&topcodes, config).parse()
+        );
+    }
+
+    #[test]
+    fn it_rectifies_a_tilted_camera() {
+        // The far edge of the table (y = 0) is squeezed into the middle of the image.
+        let table = [(0.0, 0.0), (600.0, 0.0), (600.0, 600.0), (0.0, 600.0)];
+        let image = [(200.0, 0.0), (400.0, 0.0), (600.0, 300.0), (0.0, 300.0)];
+        let camera = Homography::from_points(table, image).unwrap();
+        let topcodes = (0..6)
+            .map(|row| {
+                let code = if row == 0 {
+                    TokenCode::Start
+                } else {
+                    TokenCode::MoveForwards
+                };
+                // A row running away from the camera.
+                let topcode = TopCode::mock(code.value(), 6.0, PI / 2.0, 300.0, row as f64 * 100.0);
+                camera.rectify(&topcode).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let moves = |count| {
+            let mut builder = flow();
+            for _ in 0..count {
+                builder.with_command(Command::MoveForwards);
+            }
+            Some(Start {
+                next: builder.build(),
+            })
+        };
+        assert_ne!(moves(5), Parser::new(&topcodes).parse());
+
+        let config = ParserConfig {
+            homography: Homography::from_points(image, table),
+            ..Default::default()
+        };
+        assert_eq!(moves(5), Parser::with_config(&topcodes, config).parse());
+    }
+
+    #[test]
+    fn it_corrects_a_wide_angle_lens() {
+        let lens = LensDistortion {
+            k1: -0.15,
+            ..LensDistortion::new(320.0, 240.0, 300.0)
+        };
+        let topcodes = (0..6)
+            .map(|column| {
+                let code = if column == 0 {
+                    TokenCode::Start
+                } else {
+                    TokenCode::Shoot
+                };
+                let topcode =
+                    TopCode::mock(code.value(), 6.0, 0.0, 50.0 + column as f64 * 100.0, 30.0);
+                lens.distort_topcode(&topcode).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let config = ParserConfig {
+            distortion: Some(lens),
+            ..Default::default()
+        };
+        let mut shots = flow();
+        for _ in 0..5 {
+            shots.with_command(Command::Shoot);
+        }
+        let shots = Some(Start {
+            next: shots.build(),
+        });
+        // The bent row still links, but only just.
+        let bent = Parser::new(&topcodes).parse_report();
+        let corrected = Parser::with_config(&topcodes, config).parse_report();
+        assert_eq!(shots, corrected.start);
+        assert!(corrected.confidence() > 0.9);
+        assert!(bent.confidence() < 0.8);
+    }
+
+    #[test]
+    fn it_records_the_span_of_every_node() {
+        let topcodes = complex_topcodes();
+        let report = Parser::new(&topcodes).parse_report();
+        // One node for each of the 15 tokens, including the method parameters.
+        assert_eq!(15, report.spans.len());
+        let start = report.spans.start().unwrap();
+        assert_eq!(TokenCode::Start, start.code);
+        assert_eq!(0, start.index);
+        for (_, span) in report.spans.iter() {
+            let topcode = topcodes[span.index];
+            assert_eq!(Some(span.code.value()), topcode.code);
+            assert_eq!((topcode.x, topcode.y), (span.x, span.y));
+        }
+        // The first repeat is followed by its value, then its body.
+        let codes = report
+            .spans
+            .iter()
+            .map(|(_, span)| span.code)
+            .skip(8)
+            .take(3)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![TokenCode::Repeat, TokenCode::Value6, TokenCode::Shoot],
+            codes
+        );
+    }
+
+    #[test]
+    fn it_has_no_confidence_at_the_edge_of_either_tolerance() {
+        let config = ParserConfig::default();
+        let distance = config.displacement_squared_tolerance.sqrt();
+        let fit = |x: f64, orientation: f64| {
+            Fit::new(
+                &Token::new(TokenCode::Shoot, 48.0, orientation, x, 0.0),
+                0.0,
+                0.0,
+                0.0,
+            )
+        };
+        assert_eq!(1.0, fit(0.0, 0.0).confidence(&config));
+        assert_eq!(0.5, fit(distance / 2.0, 0.0).confidence(&config));
+        assert_eq!(0.0, fit(distance, 0.0).confidence(&config));
+        assert_eq!(0.0, fit(0.0, config.angle_tolerance).confidence(&config));
+        assert_eq!(
+            0.0,
+            fit(distance, config.angle_tolerance).confidence(&config)
+        );
+        assert!(fit(distance, config.angle_tolerance).within_threshold(&config));
+    }
+
+    #[test]
+    fn it_scores_the_confidence_of_every_link() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Value2.value(), 6.0, 0.0, 100.0, 100.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 200.0, 10.0),
+        ])
+        .parse_report();
+        let links = report
+            .confidences
+            .iter()
+            .map(|link| (link.parent.0, link.edge, link.child.0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![(0, Edge::Next, 1), (1, Edge::Value, 2), (1, Edge::Next, 3)],
+            links
+        );
+        let confidences = report
+            .confidences
+            .iter()
+            .map(|link| link.confidence)
+            .collect::<Vec<_>>();
+        assert_eq!(1.0, confidences[0]);
+        assert_eq!(1.0, confidences[1]);
+        assert!(0.0 < confidences[2] && confidences[2] < 1.0);
+        assert_eq!(confidences[2], report.confidence());
+
+        assert_eq!(0.0, Parser::new(&[]).parse_report().confidence());
+    }
+
+    #[test]
+    fn it_ignores_an_outlying_diameter() {
+        // A misdetection far larger than the rest of the scene.
+        let mut topcodes = (0..5)
+            .map(|i| TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, i as f64 * 100.0, 0.0))
+            .collect::<Vec<_>>();
+        topcodes[0].code = Some(TokenCode::Start.value());
+        topcodes.push(TopCode::mock(
+            TokenCode::Value2.value(),
+            60.0,
+            0.0,
+            0.0,
+            1000.0,
+        ));
+        let shoots = Some(Start {
+            next: flow()
+                .with_command(Command::Shoot)
+                .with_command(Command::Shoot)
+                .with_command(Command::Shoot)
+                .with_command(Command::Shoot)
+                .build(),
+        });
+        assert_eq!(shoots, Parser::new(&topcodes).parse());
+
+        let config = ParserConfig {
+            diameter: DiameterEstimate::Mean,
+            ..Default::default()
+        };
+        // The inflated diameter makes every link overshoot, skipping tokens.
+        assert_ne!(shoots, Parser::with_config(&topcodes, config).parse());
+    }
+
+    #[test]
+    fn it_can_keep_the_diameter_of_each_token() {
+        // Tokens shrinking into the distance, as seen by a camera at an angle.
+        let topcodes = vec![
+            TopCode::mock(TokenCode::Start.value(), 12.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::MoveForwards.value(), 6.0, 0.0, 200.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 3.0, 0.0, 300.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 3.0, 0.0, 350.0, 0.0),
+        ];
+        assert_eq!(Some(Start { next: None }), Parser::new(&topcodes).parse());
+
+        let config = ParserConfig {
+            diameter: DiameterEstimate::PerToken,
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(Start {
+                next: flow()
+                    .with_command(Command::MoveForwards)
+                    .with_command(Command::Shoot)
+                    .with_command(Command::Shoot)
+                    .build(),
+            }),
+            Parser::with_config(&topcodes, config).parse()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn it_does_not_depend_on_the_order_of_the_topcodes(
+            (topcodes, shuffled) in prop_oneof![
+                Just(complex_topcodes()),
+                Just(contested_topcodes()),
+            ]
+            .prop_flat_map(|topcodes| (Just(topcodes.clone()), Just(topcodes).prop_shuffle()))
+        ) {
+            let report = Parser::new(&topcodes).parse_report();
+            let shuffled_report = Parser::new(&shuffled).parse_report();
+            prop_assert_eq!(&report.start, &shuffled_report.start);
+            prop_assert_eq!(report.diagnostics.len(), shuffled_report.diagnostics.len());
+            for (diagnostic, shuffled_diagnostic) in report.diagnostics.iter().zip(&shuffled_report.diagnostics) {
+                prop_assert_eq!(diagnostic.kind, shuffled_diagnostic.kind);
+                prop_assert_eq!(&diagnostic.scores, &shuffled_diagnostic.scores);
+                prop_assert_eq!(diagnostic.spans.len(), shuffled_diagnostic.spans.len());
+                for (span, shuffled_span) in diagnostic.spans.iter().zip(&shuffled_diagnostic.spans) {
+                    prop_assert_eq!(topcodes[span.index], shuffled[shuffled_span.index]);
+                }
+            }
+            // The spans point at the same TopCodes, wherever they ended up in the input.
+            for ((_, span), (_, shuffled_span)) in report.spans.iter().zip(shuffled_report.spans.iter()) {
+                prop_assert_eq!(topcodes[span.index], shuffled[shuffled_span.index]);
+            }
+        }
+
+        #[test]
+        fn it_never_panics(topcodes in prop::collection::vec(topcode(), 0..64)) {
+            let _ = Parser::new(&topcodes).parse_report();
+            let _ = Parser::new(&topcodes).try_parse();
+        }
+    }
+
+    #[test]
+    fn it_finds_the_same_candidates_by_scanning_every_token() {
+        for topcodes in [complex_topcodes(), contested_topcodes()] {
+            let config = ParserConfig {
+                trace: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                Parser::with_config(&topcodes, config.clone()).parse_report(),
+                Parser::with_config(&topcodes, config)
+                    .with_linear_scan()
+                    .parse_report()
+            );
+        }
+    }
+
+    #[test]
+    fn it_gives_a_contested_token_to_the_closest_slot() {
+        // The shoot token sits exactly in the body of the repeat, but is also slightly ahead of
+        // the turn left token.
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, -PI / 2.0, 88.0, 32.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, -PI / 2.0, 88.0, -88.0),
+        ])
+        .parse_report();
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow::new(FlowKind::IntegerMethod(IntegerMethod {
+                    kind: IntegerMethodKind::Repeat,
+                    body: Some(Box::new(Flow::new(FlowKind::Command(Command::Shoot)))),
+                    value: None,
+                })))
+            }),
+            report.start
+        );
+        let conflicts = report
+            .diagnostics_of(DiagnosticKind::Conflict)
+            .map(|diagnostic| {
+                diagnostic
+                    .spans
+                    .iter()
+                    .map(|span| span.code)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![vec![
+                TokenCode::Shoot,
+                TokenCode::Repeat,
+                TokenCode::TurnLeft
+            ]],
+            conflicts
+        );
+    }
+
+    #[test]
+    fn it_traces_every_lookup_when_enabled() {
+        let topcodes = [
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, -PI / 2.0, 88.0, 32.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, -PI / 2.0, 88.0, -88.0),
+        ];
+        assert_eq!(None, Parser::new(&topcodes).parse_report().trace);
+
+        let config = ParserConfig {
+            trace: true,
+            ..Default::default()
+        };
+        let trace = Parser::with_config(&topcodes, config)
+            .parse_report()
+            .trace
+            .unwrap();
+        let lookup = |code, slot| {
+            trace
+                .lookups
+                .iter()
+                .find(|lookup| lookup.owner.code == code && lookup.slot == slot)
+                .unwrap()
+        };
+        let verdicts = |lookup: &LookupTrace| {
+            lookup
+                .candidates
+                .iter()
+                .map(|candidate| (candidate.token.index, candidate.verdict))
+                .collect::<Vec<_>>()
+        };
+
+        let start = lookup(TokenCode::Start, Slot::Adjacent);
+        assert_eq!(
+            (100.0, 0.0, 0.0),
+            (
+                start.expected_x.round(),
+                start.expected_y.round(),
+                start.expected_angle
+            )
+        );
+        assert_eq!(
+            vec![
+                (2, Verdict::Linked),
+                (1, Verdict::OutOfTolerance),
+                (3, Verdict::OutOfTolerance)
+            ],
+            verdicts(start)
+        );
+        assert_eq!(
+            vec![(3, Verdict::Linked)],
+            verdicts(lookup(TokenCode::Repeat, Slot::Body))
+        );
+        assert_eq!(
+            vec![(3, Verdict::LinkedElsewhere), (2, Verdict::OutOfTolerance)],
+            verdicts(lookup(TokenCode::TurnLeft, Slot::Adjacent))
+        );
+        let json = trace.to_json();
+        assert!(json.contains(r#""slot":"adjacent""#));
+        assert!(json.contains(r#""verdict":"linkedElsewhere""#));
+        assert!(json.starts_with(r#"{"lookups":[{"owner":{"code":59,"#));
+        assert!(json.contains(r#""expectedAngle":"#));
+    }
+
+    #[test]
+    fn it_only_uses_a_parameter_once() {
+        let report = Parser::new(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, -100.0, 0.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Value2.value(), 6.0, 0.0, 45.0, 100.0),
+        ])
+        .parse_report();
+        let repeat = |value| {
+            FlowKind::IntegerMethod(IntegerMethod {
+                kind: IntegerMethodKind::Repeat,
+                body: None,
+                value,
+            })
+        };
+        assert_eq!(
+            Some(Start {
+                next: Some(Flow {
+                    kind: repeat(Some(Value::Two)),
+                    next: Some(Box::new(Flow::new(repeat(None)))),
+                })
+            }),
+            report.start
+        );
+        assert_eq!(1, report.diagnostics_of(DiagnosticKind::Conflict).count());
+    }
 }
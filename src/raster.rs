@@ -0,0 +1,429 @@
+use std::f64::consts::PI;
+
+use topcodes::{Scanner, TopCode};
+
+use crate::{
+    svg::{sector_angle, to_image, SECTORS},
+    Homography, ImageConfig, ImageError, ParserConfig, Token,
+};
+
+/// The brightness of the table, the paper of the tokens and the ink of the TopCodes, between 0 and
+/// 1.
+const TABLE: f64 = 0.45;
+const PAPER: f64 = 0.92;
+const INK: f64 = 0.08;
+
+/// A greyscale image of tokens on a table, as drawn by [`crate::render_image`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SceneImage {
+    pub width: usize,
+    pub height: usize,
+    /// The brightness of each pixel, row by row, from 0 for black to 255 for white.
+    pub pixels: Vec<u8>,
+    /// Maps the image back onto the positions of the tokens, undoing the scale and skew. Set it as
+    /// [`ParserConfig::homography`] to parse the scanned TopCodes in the units of the tokens.
+    pub homography: Homography,
+}
+
+impl SceneImage {
+    /// Scans the image for TopCodes, in the same way as a frame from a camera.
+    pub fn scan(&self) -> Vec<TopCode> {
+        Scanner::new(self.width, self.height).scan(&self.pixels[..], |pixels, index| {
+            let pixel = pixels[index] as u32;
+            (pixel, pixel, pixel)
+        })
+    }
+}
+
+/// A token as it is drawn, in the image convention of the TopCodes.
+struct Shape {
+    outline: Vec<(f64, f64)>,
+    min: (f64, f64),
+    max: (f64, f64),
+    code: u32,
+    centre: (f64, f64),
+    unit: f64,
+    orientation: f64,
+}
+
+impl Shape {
+    fn new(token: &Token, config: &ParserConfig) -> Self {
+        let ratio = token.ratio(config.topcode_diameter());
+        let outline = config
+            .outline(token.code)
+            .into_iter()
+            .map(|point| to_image(token, point, ratio))
+            .collect::<Vec<_>>();
+        let (min, max) = bounds(&outline);
+        let topcode = token.to_topcode();
+        Self {
+            outline,
+            min,
+            max,
+            code: token.code.value(),
+            centre: (topcode.x, topcode.y),
+            unit: topcode.unit,
+            orientation: topcode.orientation,
+        }
+    }
+
+    /// The brightness of the token at the point, or `None` if the point is not on the token.
+    fn shade(&self, (x, y): (f64, f64)) -> Option<f64> {
+        if x < self.min.0 || x > self.max.0 || y < self.min.1 || y > self.max.1 {
+            return None;
+        }
+        // Counts the edges crossed by a ray to the right of the point.
+        let mut inside = false;
+        for (index, &(ax, ay)) in self.outline.iter().enumerate() {
+            let (bx, by) = self.outline[(index + 1) % self.outline.len()];
+            if (ay > y) != (by > y) && x < ax + (bx - ax) * (y - ay) / (by - ay) {
+                inside = !inside;
+            }
+        }
+        if !inside {
+            return None;
+        }
+        let (dx, dy) = (x - self.centre.0, y - self.centre.1);
+        // The rings of the TopCode, as read by the scanner, one unit wide each.
+        let ring = dx.hypot(dy) / self.unit;
+        let shade = if ring < 1.0 || (2.0..3.0).contains(&ring) || ring >= 4.0 {
+            PAPER
+        } else if ring < 2.0 {
+            INK
+        } else {
+            let arc = PI * 2.0 / SECTORS as f64;
+            let offset = (dy.atan2(dx) - sector_angle(self.orientation, 0)) / arc;
+            let sector = offset.round().rem_euclid(SECTORS as f64) as u32;
+            if (self.code >> sector) & 1 == 1 {
+                PAPER
+            } else {
+                INK
+            }
+        };
+        Some(shade)
+    }
+}
+
+fn bounds(points: &[(f64, f64)]) -> ((f64, f64), (f64, f64)) {
+    points.iter().fold(
+        (
+            (f64::INFINITY, f64::INFINITY),
+            (f64::NEG_INFINITY, f64::NEG_INFINITY),
+        ),
+        |(min, max), &(x, y)| ((min.0.min(x), min.1.min(y)), (max.0.max(x), max.1.max(y))),
+    )
+}
+
+/// Checks that every setting is a number in its range.
+fn check(config: &ImageConfig) -> Result<(), ImageError> {
+    let settings = [
+        ("scale", config.scale, config.scale > 0.0),
+        ("margin", config.margin, config.margin >= 0.0),
+        ("blur", config.blur, config.blur >= 0.0),
+        ("noise", config.noise, config.noise >= 0.0),
+        (
+            "lighting",
+            config.lighting,
+            (0.0..=1.0).contains(&config.lighting),
+        ),
+        ("skew", config.skew, (0.0..1.0).contains(&config.skew)),
+    ];
+    match settings
+        .into_iter()
+        .find(|&(_, value, valid)| !valid || !value.is_finite())
+    {
+        Some((name, value, _)) => Err(ImageError::InvalidSetting { name, value }),
+        None => Ok(()),
+    }
+}
+
+/// Draws the tokens as a camera would see them, with the table filling the image.
+pub(crate) fn render(tokens: &[Token], config: &ImageConfig) -> Result<SceneImage, ImageError> {
+    check(config)?;
+    let shapes = tokens
+        .iter()
+        .filter(|token| token.is_well_formed())
+        .map(|token| Shape::new(token, &config.parser))
+        .collect::<Vec<_>>();
+    let corners = shapes
+        .iter()
+        .flat_map(|shape| [shape.min, shape.max])
+        .collect::<Vec<_>>();
+    let (min, max) = match bounds(&corners) {
+        (min, max) if min.0 <= max.0 => (min, max),
+        _ => ((0.0, 0.0), (0.0, 0.0)),
+    };
+    let (min, max) = (
+        (min.0 - config.margin, min.1 - config.margin),
+        (max.0 + config.margin, max.1 + config.margin),
+    );
+    let (w, h) = (
+        ((max.0 - min.0) * config.scale).ceil(),
+        ((max.1 - min.1) * config.scale).ceil(),
+    );
+    if !(w > 0.0 && h > 0.0) {
+        return Err(ImageError::Empty);
+    }
+    if w * h > config.max_pixels as f64 {
+        return Err(ImageError::TooLarge {
+            width: w,
+            height: h,
+        });
+    }
+    let (width, height) = (w as usize, h as usize);
+    // The far edge of the table is narrowed into the middle of the top of the image.
+    let inset = config.skew * w / 2.0;
+    let homography = Homography::from_points(
+        [(inset, 0.0), (w - inset, 0.0), (w, h), (0.0, h)],
+        [
+            (min.0, min.1),
+            (max.0, min.1),
+            (max.0, max.1),
+            (min.0, max.1),
+        ],
+    )
+    .ok_or(ImageError::Empty)?;
+
+    // Each pixel is the average of a few samples, so that edges are smooth.
+    const SAMPLES: [f64; 2] = [0.25, 0.75];
+    let mut image = vec![0.0; width * height];
+    for (index, pixel) in image.iter_mut().enumerate() {
+        let (x, y) = ((index % width) as f64, (index / width) as f64);
+        let mut sum = 0.0;
+        for dy in SAMPLES {
+            for dx in SAMPLES {
+                sum += homography
+                    .apply(x + dx, y + dy)
+                    .and_then(|point| shapes.iter().rev().find_map(|shape| shape.shade(point)))
+                    .unwrap_or(TABLE);
+            }
+        }
+        *pixel = sum / (SAMPLES.len() * SAMPLES.len()) as f64;
+    }
+
+    blur(&mut image, width, height, config.blur);
+    let mut random = Random(config.seed);
+    let pixels = image
+        .iter()
+        .enumerate()
+        .map(|(index, &shade)| {
+            let (x, y) = ((index % width) as f64 / w, (index / width) as f64 / h);
+            let light = 1.0 - config.lighting * (x + y) / 2.0;
+            let value = shade * light * 255.0 + random.normal() * config.noise;
+            value.round().clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    Ok(SceneImage {
+        width,
+        height,
+        pixels,
+        homography,
+    })
+}
+
+/// Blurs the image in place with a Gaussian of the given standard deviation, one axis at a time.
+fn blur(image: &mut [f64], width: usize, height: usize, sigma: f64) {
+    if sigma <= 0.0 {
+        return;
+    }
+    let radius = (sigma * 3.0).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|offset| (-(offset * offset) as f64 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total = kernel.iter().sum::<f64>();
+    // Pixels past the edge take the value of the nearest pixel on the edge.
+    let pass = |image: &[f64], step: (isize, isize)| {
+        let mut blurred = vec![0.0; image.len()];
+        for (index, pixel) in blurred.iter_mut().enumerate() {
+            let (x, y) = ((index % width) as isize, (index / width) as isize);
+            *pixel = kernel
+                .iter()
+                .zip(-radius..=radius)
+                .map(|(weight, offset)| {
+                    let sx = (x + offset * step.0).clamp(0, width as isize - 1) as usize;
+                    let sy = (y + offset * step.1).clamp(0, height as isize - 1) as usize;
+                    weight * image[sy * width + sx]
+                })
+                .sum::<f64>()
+                / total;
+        }
+        blurred
+    };
+    let horizontal = pass(image, (1, 0));
+    image.copy_from_slice(&pass(&horizontal, (0, 1)));
+}
+
+/// A small generator of pseudo-random numbers (SplitMix64), so that images can be reproduced from
+/// a seed without another dependency.
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform number in (0, 1].
+    fn uniform(&mut self) -> f64 {
+        ((self.next() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// A number from the standard normal distribution, by the Box-Muller transform.
+    fn normal(&mut self) -> f64 {
+        let (u, v) = (self.uniform(), self.uniform());
+        (-2.0 * u.ln()).sqrt() * (PI * 2.0 * v).cos()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::{
+            BooleanMethodKind, Command, Condition, ConditionalKind, IntegerMethodKind, Start, Value,
+        },
+        flow, layout_tokens, parse_with, start, TokenCode,
+    };
+
+    fn program() -> Start {
+        start()
+            .with_integer_method(
+                IntegerMethodKind::Repeat,
+                Some(Value::Three),
+                flow()
+                    .with_command(Command::MoveForwards)
+                    .with_command(Command::TurnLeft)
+                    .build(),
+            )
+            .with_conditional(
+                ConditionalKind::Blocked,
+                flow().with_command(Command::TurnRight).build(),
+            )
+            .with_boolean_method(
+                BooleanMethodKind::While,
+                Some(Condition::IsPathClear),
+                flow().with_command(Command::Shoot).build(),
+            )
+            .with_command(Command::MoveBackwards)
+            .build()
+    }
+
+    /// Lays out and draws the program, then scans the image and parses the TopCodes found, giving
+    /// the number of TopCodes expected and found along with the program.
+    fn round_trip(config: &ImageConfig) -> (usize, usize, Option<Start>) {
+        let tokens = layout_tokens(&program()).unwrap();
+        let image = render(&tokens, config).unwrap();
+        let topcodes = image.scan();
+        let parser = ParserConfig {
+            homography: Some(image.homography),
+            ..config.parser.clone()
+        };
+        (tokens.len(), topcodes.len(), parse_with(&topcodes, &parser))
+    }
+
+    #[test]
+    fn it_draws_a_program_which_scans_and_parses_back() {
+        let tokens = layout_tokens(&program()).unwrap();
+        let image = render(&tokens, &ImageConfig::default()).unwrap();
+        assert_eq!(image.width * image.height, image.pixels.len());
+        let mut codes = image
+            .scan()
+            .iter()
+            .map(|topcode| topcode.code.unwrap())
+            .collect::<Vec<_>>();
+        let mut expected = tokens
+            .iter()
+            .map(|token| token.code.value())
+            .collect::<Vec<_>>();
+        codes.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(expected, codes);
+
+        let (expected, found, start) = round_trip(&ImageConfig::default());
+        assert_eq!(expected, found);
+        assert_eq!(Some(program()), start);
+    }
+
+    #[test]
+    fn it_draws_a_difficult_camera() {
+        let config = ImageConfig {
+            scale: 1.2,
+            blur: 1.0,
+            noise: 8.0,
+            lighting: 0.5,
+            skew: 0.2,
+            seed: 7,
+            ..Default::default()
+        };
+        let (expected, found, start) = round_trip(&config);
+        assert_eq!(expected, found);
+        assert_eq!(Some(program()), start);
+    }
+
+    #[test]
+    fn it_draws_the_same_image_from_the_same_seed() {
+        let tokens = layout_tokens(&start().with_command(Command::Shoot).build()).unwrap();
+        let config = ImageConfig::default();
+        assert_eq!(render(&tokens, &config), render(&tokens, &config));
+        assert!(render(&tokens, &config).is_ok());
+        let reseeded = ImageConfig {
+            seed: 1,
+            ..Default::default()
+        };
+        assert_ne!(
+            render(&tokens, &config).unwrap().pixels,
+            render(&tokens, &reseeded).unwrap().pixels
+        );
+    }
+
+    #[test]
+    fn it_rejects_images_which_cannot_be_drawn() {
+        let tokens = layout_tokens(&start().with_command(Command::Shoot).build()).unwrap();
+        for (name, config) in [
+            (
+                "scale",
+                ImageConfig {
+                    scale: 0.0,
+                    ..Default::default()
+                },
+            ),
+            (
+                "margin",
+                ImageConfig {
+                    margin: f64::NAN,
+                    ..Default::default()
+                },
+            ),
+            (
+                "skew",
+                ImageConfig {
+                    skew: 1.0,
+                    ..Default::default()
+                },
+            ),
+        ] {
+            assert!(matches!(
+                render(&tokens, &config),
+                Err(ImageError::InvalidSetting { name: found, .. }) if found == name
+            ));
+        }
+
+        // A stray token far away from the others.
+        let mut stray = tokens.clone();
+        stray.push(Token::new(TokenCode::Shoot, 48.0, 0.0, 1e9, 1e9));
+        assert!(matches!(
+            render(&stray, &ImageConfig::default()),
+            Err(ImageError::TooLarge { .. })
+        ));
+
+        let config = ImageConfig {
+            margin: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(Err(ImageError::Empty), render(&[], &config));
+        assert!(render(&[], &ImageConfig::default()).is_ok());
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{
+    ast::{Flow, Start},
+    Diagnostic, LinkConfidence, ParseReport, SourceMap,
+};
+
+/// Everything on the table: the program, and every chain of blocks which is not connected to it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scene {
+    /// The program, exactly as reported by [`crate::parse_report`].
+    pub program: ParseReport,
+    /// The chains of flow tokens which are not part of the program, e.g. because the start token
+    /// is missing or the chain was broken in the middle. They are in reading order of their first
+    /// token.
+    pub fragments: Vec<Fragment>,
+}
+
+/// A chain of flow tokens which is not connected to the program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub flow: Flow,
+    /// The spans of the fragment, where the first flow is node 0.
+    pub spans: SourceMap,
+}
+
+/// One of several programs on the table, e.g. one per player.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub start: Start,
+    /// Everything which looked wrong in the program, in the same way as
+    /// [`ParseReport::diagnostics`]. Tokens which are not part of any program are not reported.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The spans of the program. The span of the start node tells the programs apart.
+    pub spans: SourceMap,
+    /// How well every link of the program fit, in pre-order of the child nodes.
+    pub confidences: Vec<LinkConfidence>,
+}
+
+impl Program {
+    /// The confidence in the program as a whole, in the same way as [`ParseReport::confidence`].
+    pub fn confidence(&self) -> f64 {
+        self.confidences
+            .iter()
+            .map(|link| link.confidence)
+            .fold(1.0, f64::min)
+    }
+}
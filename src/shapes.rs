@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ParserConfig, ShapeError, Slot, Token, TokenCode};
+
+/// A point on a token at which another token connects, given as the position and orientation the
+/// connected token's TopCode is expected at.
+///
+/// The offset is relative to the TopCode of the token, in the same units as
+/// [`crate::ParserConfig::token_size`], with x pointing in the direction the token faces and y
+/// pointing 90° anticlockwise from it (see [`crate::Token`]).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Connection {
+    pub slot: Slot,
+    pub x: f64,
+    pub y: f64,
+    /// The orientation of the connected token relative to this one, in radians, anticlockwise.
+    pub angle: f64,
+}
+
+impl Connection {
+    /// The position and orientation at which the connected token is expected, for the given token.
+    pub(crate) fn target(&self, token: &Token, config: &ParserConfig) -> (f64, f64, f64) {
+        let ratio = token.ratio(config.topcode_diameter());
+        let (sin_angle, cos_angle) = token.orientation.sin_cos();
+        (
+            token.x + (self.x * cos_angle - self.y * sin_angle) * ratio,
+            token.y + (self.x * sin_angle + self.y * cos_angle) * ratio,
+            token.orientation + self.angle,
+        )
+    }
+}
+
+/// The connection points of one kind of token.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenShape {
+    pub connections: Vec<Connection>,
+}
+
+impl TokenShape {
+    pub fn new(connections: Vec<Connection>) -> Self {
+        Self { connections }
+    }
+
+    pub fn connection(&self, slot: Slot) -> Option<&Connection> {
+        self.connections
+            .iter()
+            .find(|connection| connection.slot == slot)
+    }
+}
+
+/// The shape of every kind of token, so that tokens with a different physical form factor can be
+/// parsed without changing the parser. Connections are only followed for the slots the grammar
+/// allows on that kind of token, e.g. a command only ever has a next token.
+///
+/// In TOML, which needs the `toml` feature, each token is a table named after its [`TokenCode`]:
+///
+/// ```toml
+/// [Repeat]
+/// connections = [
+///   { slot = "adjacent", x = 100.0, y = 0.0, angle = 0.0 },
+///   { slot = "body", x = -12.0, y = 88.0, angle = 1.5707963267948966 },
+///   { slot = "parameter", x = 0.0, y = -100.0, angle = 0.0 },
+/// ]
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TokenShapes {
+    shapes: HashMap<TokenCode, TokenShape>,
+}
+
+impl TokenShapes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, code: TokenCode) -> Option<&TokenShape> {
+        self.shapes.get(&code)
+    }
+
+    /// Sets the shape of a kind of token, returning its previous shape.
+    pub fn insert(&mut self, code: TokenCode, shape: TokenShape) -> Option<TokenShape> {
+        self.shapes.insert(code, shape)
+    }
+
+    /// Replaces the shapes of every kind of token in the other shapes, keeping the rest.
+    pub fn extend(&mut self, other: TokenShapes) {
+        self.shapes.extend(other.shapes);
+    }
+
+    /// The connection for the given slot of a kind of token, if it has one.
+    pub fn connection(&self, code: TokenCode, slot: Slot) -> Option<&Connection> {
+        self.get(code)?.connection(slot)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(source: &str) -> Result<Self, ShapeError> {
+        let shapes: Self = toml::from_str(source).map_err(ShapeError::new)?;
+        shapes.validate()
+    }
+
+    pub fn from_json(source: &str) -> Result<Self, ShapeError> {
+        let shapes: Self = serde_json::from_str(source).map_err(ShapeError::new)?;
+        shapes.validate()
+    }
+
+    /// Rejects shapes the parser cannot use, which are otherwise only found out when nothing
+    /// links.
+    fn validate(self) -> Result<Self, ShapeError> {
+        for (code, shape) in &self.shapes {
+            for (index, connection) in shape.connections.iter().enumerate() {
+                let values = [connection.x, connection.y, connection.angle];
+                if values.iter().any(|value| !value.is_finite()) {
+                    return Err(ShapeError::new(format!(
+                        "the {:?} connection of {:?} is not finite",
+                        connection.slot, code
+                    )));
+                }
+                if shape.connections[..index]
+                    .iter()
+                    .any(|other| other.slot == connection.slot)
+                {
+                    return Err(ShapeError::new(format!(
+                        "{:?} has more than one {:?} connection",
+                        code, connection.slot
+                    )));
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shoot_shapes() -> TokenShapes {
+        let mut shapes = TokenShapes::new();
+        shapes.insert(
+            TokenCode::Shoot,
+            TokenShape::new(vec![Connection {
+                slot: Slot::Adjacent,
+                x: 120.0,
+                y: 0.0,
+                angle: 0.0,
+            }]),
+        );
+        shapes
+    }
+
+    #[test]
+    fn it_loads_shapes_from_json() {
+        let json = r#"{
+            "Shoot": { "connections": [{ "slot": "adjacent", "x": 120.0, "y": 0.0, "angle": 0.0 }] }
+        }"#;
+        assert_eq!(Ok(shoot_shapes()), TokenShapes::from_json(json));
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn it_loads_shapes_from_toml() {
+        let toml = r#"
+            [Shoot]
+            connections = [{ slot = "adjacent", x = 120.0, y = 0.0, angle = 0.0 }]
+        "#;
+        assert_eq!(Ok(shoot_shapes()), TokenShapes::from_toml(toml));
+    }
+
+    #[test]
+    fn it_round_trips_the_standard_shapes() {
+        let shapes = ParserConfig::default().token_shapes();
+        let json = serde_json::to_string(&shapes).unwrap();
+        assert_eq!(Ok(shapes.clone()), TokenShapes::from_json(&json));
+        #[cfg(feature = "toml")]
+        {
+            let toml = toml::to_string(&shapes).unwrap();
+            assert_eq!(Ok(shapes), TokenShapes::from_toml(&toml));
+        }
+    }
+
+    #[test]
+    fn it_rejects_unusable_shapes() {
+        assert!(TokenShapes::from_json(r#"{"Shoot": {"connections": 3}}"#).is_err());
+        assert!(TokenShapes::from_json(r#"{"Nope": {"connections": []}}"#).is_err());
+        let duplicate = r#"{
+            "Shoot": { "connections": [
+                { "slot": "adjacent", "x": 100.0, "y": 0.0, "angle": 0.0 },
+                { "slot": "adjacent", "x": 120.0, "y": 0.0, "angle": 0.0 }
+            ] }
+        }"#;
+        assert!(TokenShapes::from_json(duplicate).is_err());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn it_rejects_unusable_toml_shapes() {
+        assert!(TokenShapes::from_toml("[Shoot]\nconnections = 3").is_err());
+        let infinite = r#"
+            [Shoot]
+            connections = [{ slot = "adjacent", x = inf, y = 0.0, angle = 0.0 }]
+        "#;
+        assert!(TokenShapes::from_toml(infinite).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::f64::consts::PI;
+
+use serde::{Serialize, Serializer};
+
+use crate::{Token, TokenCode};
+
+/// Where a node of the AST came from in the scene, so that the physical token can be highlighted.
+/// Positions are in image coordinates, as reported by the TopCode scanner, or in table coordinates
+/// when the parser was given a [`crate::Homography`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Span {
+    /// Serialized as the number of the TopCode, so that it can be read without knowing the names
+    /// of the tokens.
+    #[serde(serialize_with = "serialize_code")]
+    pub code: TokenCode,
+    pub x: f64,
+    pub y: f64,
+    /// The orientation of the TopCode in radians, between 0 and 2π.
+    pub orientation: f64,
+    /// The diameter the parser used for the token, which may be an estimate across the scene. See
+    /// [`crate::DiameterEstimate`].
+    pub diameter: f64,
+    /// The index of the TopCode in the slice given to the parser.
+    pub index: usize,
+}
+
+impl Span {
+    /// The token the parser saw, back in the convention of [`Token`].
+    pub(crate) fn token(&self) -> Token {
+        Token::new(
+            self.code,
+            self.diameter,
+            (-self.orientation).rem_euclid(PI * 2.0),
+            self.x,
+            -self.y,
+        )
+    }
+}
+
+fn serialize_code<S: Serializer>(code: &TokenCode, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u32(code.value())
+}
+
+/// Identifies a node of the AST by its position in a pre-order walk of the tree. The start node is
+/// always 0, and every flow node is followed by its parameter (the value or condition of a
+/// method), its body, its alternate and finally its next node, skipping any which are missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(pub usize);
+
+/// The spans of every node of a parsed AST, keyed by [`NodeId`]. The AST itself stays free of
+/// locations, so it can still be built and compared by hand.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    spans: Vec<Span>,
+}
+
+impl SourceMap {
+    pub fn get(&self, id: NodeId) -> Option<&Span> {
+        self.spans.get(id.0)
+    }
+
+    /// The span of the start node.
+    pub fn start(&self) -> Option<&Span> {
+        self.get(NodeId(0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Span)> {
+        self.spans
+            .iter()
+            .enumerate()
+            .map(|(id, span)| (NodeId(id), span))
+    }
+
+    /// Adds the span of the next node in pre-order.
+    pub(crate) fn push(&mut self, span: Span) -> NodeId {
+        self.spans.push(span);
+        NodeId(self.spans.len() - 1)
+    }
+}
@@ -0,0 +1,538 @@
+use std::{collections::HashSet, f64::consts::PI, fmt::Write};
+
+use crate::{Edge, NodeId, ParseReport, ParserConfig, SheetConfig, Slot, Token, TokenCode};
+
+/// The number of sectors in the data ring of a TopCode.
+pub(crate) const SECTORS: u32 = 13;
+/// The angle each sector of the data ring covers.
+const ARC: f64 = PI * 2.0 / SECTORS as f64;
+/// The space left around the drawing, in the units of the scene.
+const MARGIN: f64 = 20.0;
+
+/// The angle of the middle of a sector of the data ring, in image coordinates, for a TopCode with
+/// the given orientation. This is where the scanner reads the bit of the sector, with bit 0 just
+/// past the orientation.
+pub(crate) fn sector_angle(orientation: f64, sector: u32) -> f64 {
+    orientation + (sector as f64 + 0.65) * ARC
+}
+
+/// An SVG document in image coordinates, which grows to fit everything drawn on it.
+pub(crate) struct Svg {
+    body: String,
+    min: (f64, f64),
+    max: (f64, f64),
+}
+
+impl Svg {
+    pub fn new() -> Self {
+        Self {
+            body: String::new(),
+            min: (f64::INFINITY, f64::INFINITY),
+            max: (f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    fn fit(&mut self, (x, y): (f64, f64)) {
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+    }
+
+    /// Adds raw markup, e.g. definitions, which does not change the size of the document.
+    pub fn raw(&mut self, markup: &str) {
+        self.body.push_str(markup);
+        self.body.push('\n');
+    }
+
+    pub fn polygon(&mut self, points: &[(f64, f64)], style: &str) {
+        let mut path = String::new();
+        for &(x, y) in points {
+            self.fit((x, y));
+            let _ = write!(path, "{:.2},{:.2} ", x, y);
+        }
+        let _ = writeln!(
+            self.body,
+            r#"<polygon points="{}" {}/>"#,
+            path.trim_end(),
+            style
+        );
+    }
+
+    pub fn circle(&mut self, (x, y): (f64, f64), radius: f64, style: &str) {
+        self.fit((x - radius, y - radius));
+        self.fit((x + radius, y + radius));
+        let _ = writeln!(
+            self.body,
+            r#"<circle cx="{:.2}" cy="{:.2}" r="{:.2}" {}/>"#,
+            x, y, radius, style
+        );
+    }
+
+    pub fn line(&mut self, from: (f64, f64), to: (f64, f64), style: &str, title: &str) {
+        self.fit(from);
+        self.fit(to);
+        let _ = writeln!(
+            self.body,
+            r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" {}><title>{}</title></line>"#,
+            from.0, from.1, to.0, to.1, style, title
+        );
+    }
+
+    /// Writes the text centred on the point, turned clockwise by the angle in radians.
+    pub fn text(&mut self, (x, y): (f64, f64), angle: f64, size: f64, text: &str) {
+        let _ = writeln!(
+            self.body,
+            r#"<text x="{x:.2}" y="{y:.2}" transform="rotate({:.2} {x:.2} {y:.2})" font-family="sans-serif" font-size="{:.2}" text-anchor="middle" dominant-baseline="middle">{}</text>"#,
+            angle.to_degrees(),
+            size,
+            text,
+        );
+    }
+
+    /// Draws a TopCode with the given code, centred on the point. Like the scanner, the symbol is
+    /// eight units across: a white centre, a black ring, a white ring and then the data ring, in
+    /// which a black sector is a 0 bit.
+    pub fn topcode(&mut self, code: u32, centre: (f64, f64), unit: f64, orientation: f64) {
+        self.circle(centre, unit * 4.0, r#"fill="white""#);
+        let _ = writeln!(
+            self.body,
+            r#"<circle cx="{:.3}" cy="{:.3}" r="{:.3}" fill="none" stroke="black" stroke-width="{:.3}"/>"#,
+            centre.0,
+            centre.1,
+            unit * 1.5,
+            unit
+        );
+        let bit = |sector: u32| (code >> (sector % SECTORS)) & 1 == 1;
+        let point = |radius: f64, angle: f64| {
+            (
+                centre.0 + radius * f64::cos(angle),
+                centre.1 + radius * f64::sin(angle),
+            )
+        };
+        // Each run of 0 bits is drawn as one arc, so no seams show between the sectors.
+        for start in 0..SECTORS {
+            if bit(start) || !bit(start + SECTORS - 1) {
+                continue;
+            }
+            let mut end = start;
+            while end < start + SECTORS - 1 && !bit(end + 1) {
+                end += 1;
+            }
+            let from = sector_angle(orientation, start) - ARC / 2.0;
+            let to = sector_angle(orientation, end) + ARC / 2.0;
+            let large = u8::from(to - from > PI);
+            let (outer, inner) = (unit * 4.0, unit * 3.0);
+            let points = [
+                point(outer, from),
+                point(outer, to),
+                point(inner, to),
+                point(inner, from),
+            ];
+            let _ = writeln!(
+                self.body,
+                r#"<path d="M{:.3},{:.3} A{outer:.3},{outer:.3} 0 {large} 1 {:.3},{:.3} L{:.3},{:.3} A{inner:.3},{inner:.3} 0 {large} 0 {:.3},{:.3} Z" fill="black"/>"#,
+                points[0].0,
+                points[0].1,
+                points[1].0,
+                points[1].1,
+                points[2].0,
+                points[2].1,
+                points[3].0,
+                points[3].1,
+            );
+        }
+    }
+
+    /// Draws the outline of a token and its TopCode.
+    pub fn token(&mut self, token: &Token, config: &ParserConfig, style: &str) {
+        let ratio = token.ratio(config.topcode_diameter());
+        let outline = config
+            .outline(token.code)
+            .into_iter()
+            .map(|point| to_image(token, point, ratio))
+            .collect::<Vec<_>>();
+        self.polygon(&outline, style);
+        let topcode = token.to_topcode();
+        self.topcode(
+            token.code.value(),
+            (topcode.x, topcode.y),
+            topcode.unit,
+            topcode.orientation,
+        );
+    }
+
+    /// Labels the token with its code, between the TopCode and the right hand edge.
+    pub fn label(&mut self, token: &Token, config: &ParserConfig) {
+        let ratio = token.ratio(config.topcode_diameter());
+        let offset = (config.topcode_center_y - config.token_size - config.topcode_radius) / 2.0;
+        self.text(
+            to_image(token, (0.0, offset), ratio),
+            -token.orientation,
+            12.0 * ratio,
+            &format!("{:?}", token.code),
+        );
+    }
+
+    /// The document, sized to fit its contents.
+    pub fn finish(self) -> String {
+        let (min, max) = if self.min.0 <= self.max.0 {
+            (self.min, self.max)
+        } else {
+            ((0.0, 0.0), (0.0, 0.0))
+        };
+        self.document(
+            (
+                min.0 - MARGIN,
+                min.1 - MARGIN,
+                max.0 - min.0 + MARGIN * 2.0,
+                max.1 - min.1 + MARGIN * 2.0,
+            ),
+            "",
+        )
+    }
+
+    /// The document with the given view box, as (x, y, width, height), and extra attributes for
+    /// the root element.
+    pub fn document(self, view_box: (f64, f64, f64, f64), attributes: &str) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{:.2} {:.2} {:.2} {:.2}\"{}>\n{}</svg>\n",
+            view_box.0, view_box.1, view_box.2, view_box.3, attributes, self.body
+        )
+    }
+}
+
+/// Converts a point relative to the token, in the units of the config, into image coordinates.
+pub(crate) fn to_image(token: &Token, (x, y): (f64, f64), ratio: f64) -> (f64, f64) {
+    let (sin_angle, cos_angle) = token.orientation.sin_cos();
+    (
+        token.x + (x * cos_angle - y * sin_angle) * ratio,
+        -(token.y + (x * sin_angle + y * cos_angle) * ratio),
+    )
+}
+
+/// The name and colour of the arrows for each kind of link.
+fn arrow(edge: Edge) -> (&'static str, &'static str) {
+    match edge {
+        Edge::Next => ("next", "#2f9e44"),
+        Edge::Body => ("body", "#1c7ed6"),
+        Edge::Alternate => ("alternate", "#f76707"),
+        Edge::Condition | Edge::Value => ("parameter", "#ae3ec9"),
+    }
+}
+
+/// Draws the scene as the parser saw it: every token with its TopCode and code, an arrow for every
+/// link of the program, and a ghost wherever a token of the program could have had another token
+/// linked to it.
+/// Draws the scene and the links of the report. The tokens are by the index of the TopCode they
+/// came from, which is what [`crate::Span::index`] refers to, with `None` for TopCodes which are
+/// not tokens.
+pub(crate) fn render(
+    tokens: &[Option<Token>],
+    report: &ParseReport,
+    config: &ParserConfig,
+) -> String {
+    let shapes = config.token_shapes();
+    // The tokens of the program, by node, as they are drawn. The spans may have been corrected
+    // for the camera, so they are only used to find the tokens.
+    let nodes = report
+        .spans
+        .iter()
+        .map(|(_, span)| {
+            tokens
+                .get(span.index)
+                .copied()
+                .flatten()
+                .unwrap_or_else(|| span.token())
+        })
+        .collect::<Vec<_>>();
+    let mut svg = Svg::new();
+    let mut defs = String::from("<defs>");
+    for edge in [Edge::Next, Edge::Body, Edge::Alternate, Edge::Value] {
+        let (name, colour) = arrow(edge);
+        let _ = write!(
+            defs,
+            r#"<marker id="{}" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="5" markerHeight="5" orient="auto"><path d="M0,0 L10,5 L0,10 Z" fill="{}"/></marker>"#,
+            name, colour
+        );
+    }
+    defs.push_str("</defs>");
+    svg.raw(&defs);
+
+    let parsed = report
+        .spans
+        .iter()
+        .map(|(_, span)| span.index)
+        .collect::<HashSet<_>>();
+    let drawn = tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(index, token)| Some((index, (*token)?)))
+        .filter(|(_, token)| token.is_well_formed())
+        .collect::<Vec<_>>();
+    for (index, token) in &drawn {
+        let fill = if parsed.contains(index) {
+            "white"
+        } else {
+            "#dee2e6"
+        };
+        svg.token(
+            token,
+            config,
+            &format!(r#"fill="{}" stroke="black" stroke-width="2""#, fill),
+        );
+    }
+
+    let linked = report
+        .confidences
+        .iter()
+        .map(|link| (link.parent, link.edge))
+        .collect::<HashSet<_>>();
+    for (id, token) in nodes.iter().enumerate() {
+        for &slot in Slot::of(token) {
+            let edge = Edge::of(slot, token.code);
+            let Some(connection) = shapes.connection(token.code, slot) else {
+                continue;
+            };
+            if linked.contains(&(NodeId(id), edge)) {
+                continue;
+            }
+            let (x, y, angle) = connection.target(token, config);
+            let radius = token.diameter / 2.0;
+            let style = format!(
+                r#"fill="none" stroke="{}" stroke-width="2" stroke-dasharray="6 4""#,
+                arrow(edge).1
+            );
+            svg.circle((x, -y), radius, &style);
+            svg.line(
+                (x, -y),
+                (x + radius * angle.cos(), -(y + radius * angle.sin())),
+                &style,
+                &format!("{:?} of {:?}", slot, token.code),
+            );
+        }
+    }
+
+    for link in &report.confidences {
+        let (parent, child) = (&nodes[link.parent.0], &nodes[link.child.0]);
+        let (dx, dy) = (child.x - parent.x, child.y - parent.y);
+        let length = dx.hypot(dy);
+        // The arrow stops at the edge of the child's TopCode.
+        let shorten = (child.diameter / 2.0 / length).min(1.0);
+        let (name, colour) = arrow(link.edge);
+        svg.line(
+            (parent.x, -parent.y),
+            (child.x - dx * shorten, -(child.y - dy * shorten)),
+            &format!(
+                r#"stroke="{}" stroke-width="3" marker-end="url(#{})""#,
+                colour, name
+            ),
+            &format!("{:?} {:.2}", link.edge, link.confidence),
+        );
+    }
+
+    for (_, token) in &drawn {
+        svg.label(token, config);
+    }
+    svg.finish()
+}
+
+/// Draws the tokens for printing, facing right in rows, on as many pages as they need. Each token
+/// is outlined with a thin line to cut along.
+pub(crate) fn sheets(codes: &[TokenCode], config: &SheetConfig) -> Vec<String> {
+    let parser = &config.parser;
+    let scale = config.token_size / parser.token_size;
+    let width = config.page_width - config.margin * 2.0;
+    let height = config.page_height - config.margin * 2.0;
+    let page_attributes = format!(
+        r#" width="{:.2}mm" height="{:.2}mm""#,
+        config.page_width, config.page_height
+    );
+    let finish = |page: Svg| {
+        page.document(
+            (0.0, 0.0, config.page_width, config.page_height),
+            &page_attributes,
+        )
+    };
+
+    let mut pages = Vec::new();
+    let mut page = Svg::new();
+    // The top left of the next token, within the margins.
+    let (mut x, mut y, mut row_height) = (0.0, 0.0, 0.0);
+    for (index, &code) in codes.iter().enumerate() {
+        let outline = parser.outline(code);
+        let (min_x, max_x, min_y, max_y) = outline.iter().fold(
+            (
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+            ),
+            |(min_x, max_x, min_y, max_y), &(x, y)| {
+                (min_x.min(x), max_x.max(x), min_y.min(y), max_y.max(y))
+            },
+        );
+        let (token_width, token_height) = ((max_x - min_x) * scale, (max_y - min_y) * scale);
+        if x > 0.0 && x + token_width > width {
+            (x, y, row_height) = (0.0, y + row_height + config.spacing, 0.0);
+        }
+        if index > 0 && y + token_height > height {
+            pages.push(finish(page));
+            page = Svg::new();
+            (x, y, row_height) = (0.0, 0.0, 0.0);
+        }
+        let token = Token::new(
+            code,
+            parser.topcode_diameter() * scale,
+            0.0,
+            config.margin + x - min_x * scale,
+            -(config.margin + y + max_y * scale),
+        );
+        page.token(
+            &token,
+            parser,
+            r#"fill="white" stroke="black" stroke-width="0.2""#,
+        );
+        page.label(&token, parser);
+        x += token_width + config.spacing;
+        row_height = f64::max(row_height, token_height);
+    }
+    if !codes.is_empty() {
+        pages.push(finish(page));
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::{Command, IntegerMethodKind, Value},
+        flow, layout_tokens, parse_report, parse_report_with, parse_tokens_report, render_svg,
+        start, Homography,
+    };
+    use enum_iterator::all;
+    use topcodes::TopCode;
+
+    #[test]
+    fn it_draws_the_links_and_missing_links_of_a_scene() {
+        let program = start()
+            .with_integer_method(
+                IntegerMethodKind::Repeat,
+                Some(Value::Two),
+                flow().with_command(Command::Shoot).build(),
+            )
+            .build();
+        let mut tokens = layout_tokens(&program).unwrap();
+        // A token which is nowhere near the program.
+        tokens.push(Token::new(TokenCode::TurnLeft, 48.0, 0.0, 1000.0, 1000.0));
+        let topcodes = tokens.iter().map(Token::to_topcode).collect::<Vec<_>>();
+        let report = parse_report(&topcodes);
+        let svg = render_svg(&topcodes, &report);
+
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(5, svg.matches("<polygon").count());
+        for code in ["Start", "Repeat", "Value2", "Shoot", "TurnLeft"] {
+            assert!(svg.contains(&format!(">{}</text>", code)));
+        }
+        assert_eq!(3, svg.matches("marker-end=").count());
+        assert_eq!(1, svg.matches("url(#body)").count());
+        assert_eq!(1, svg.matches("url(#parameter)").count());
+        // The next tokens of the repeat and the shoot token are missing.
+        assert_eq!(2, svg.matches("<title>Adjacent of").count());
+        assert_eq!(1, svg.matches("#dee2e6").count());
+    }
+
+    #[test]
+    fn it_leaves_out_topcodes_which_are_not_tokens() {
+        let program = start().with_command(Command::Shoot).build();
+        let mut topcodes = layout_tokens(&program)
+            .unwrap()
+            .iter()
+            .map(Token::to_topcode)
+            .collect::<Vec<_>>();
+        let svg = render_svg(&topcodes, &parse_report(&topcodes));
+
+        // A TopCode which is not a token, ahead of the program, so the indices of the spans no
+        // longer match the tokens once it is dropped.
+        topcodes.insert(0, TopCode::mock(5, 6.0, 0.0, 500.0, 500.0));
+        let report = parse_report(&topcodes);
+        assert_eq!(Some(program), report.start);
+        assert_eq!(svg, render_svg(&topcodes, &report));
+        assert_eq!(0, svg.matches("#dee2e6").count());
+    }
+
+    #[test]
+    fn it_only_shades_the_tokens_which_were_parsed() {
+        let program = start().with_command(Command::Shoot).build();
+        let mut tokens = layout_tokens(&program).unwrap();
+        // The same token detected twice, of which only one can be in the program.
+        tokens.push(tokens[1]);
+        let topcodes = tokens.iter().map(Token::to_topcode).collect::<Vec<_>>();
+        let svg = render_svg(&topcodes, &parse_tokens_report(&tokens));
+        assert_eq!(3, svg.matches("<polygon").count());
+        assert_eq!(1, svg.matches("#dee2e6").count());
+
+        // The spans of a rectified scene are not where the tokens were drawn.
+        let config = ParserConfig {
+            homography: Homography::from_points(
+                [(0.0, 0.0), (200.0, 0.0), (200.0, 200.0), (0.0, 200.0)],
+                [(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)],
+            ),
+            ..Default::default()
+        };
+        let tokens = layout_tokens(&program)
+            .unwrap()
+            .iter()
+            .map(|token| Token {
+                x: token.x * 2.0,
+                y: token.y * 2.0,
+                diameter: token.diameter * 2.0,
+                ..*token
+            })
+            .collect::<Vec<_>>();
+        let topcodes = tokens.iter().map(Token::to_topcode).collect::<Vec<_>>();
+        let report = parse_report_with(&topcodes, &config);
+        assert_eq!(Some(program), report.start);
+        let svg = render_svg(&topcodes, &report);
+        assert_eq!(0, svg.matches("#dee2e6").count());
+        assert_eq!(render_svg(&topcodes, &parse_tokens_report(&tokens)), svg);
+    }
+
+    #[test]
+    fn it_draws_every_token_on_printable_pages() {
+        let codes = all::<TokenCode>().collect::<Vec<_>>();
+        let config = SheetConfig::default();
+        let pages = sheets(&codes, &config);
+        assert!(pages.len() > 1);
+        let tokens = pages
+            .iter()
+            .map(|page| page.matches("<polygon").count())
+            .sum::<usize>();
+        assert_eq!(codes.len(), tokens);
+        for page in &pages {
+            assert!(page.contains(r#"width="210.00mm" height="297.00mm""#));
+            // Every point drawn is within the margins.
+            for polygon in page.split(r#"points=""#).skip(1) {
+                let points = polygon.split('"').next().unwrap();
+                for point in points.split(' ') {
+                    let (x, y) = point.split_once(',').unwrap();
+                    let (x, y) = (x.parse::<f64>().unwrap(), y.parse::<f64>().unwrap());
+                    assert!((config.margin..=config.page_width - config.margin).contains(&x));
+                    assert!((config.margin..=config.page_height - config.margin).contains(&y));
+                }
+            }
+        }
+        // The TopCodes are scaled with the tokens, to 19.2mm across.
+        assert!(pages[0].contains(r#"r="9.60""#));
+        assert!(sheets(&[], &config).is_empty());
+    }
+
+    #[test]
+    fn it_draws_the_bits_of_a_topcode() {
+        let mut svg = Svg::new();
+        // 31 has five 1 bits in a row, leaving one run of eight 0 bits.
+        svg.topcode(31, (0.0, 0.0), 6.0, 0.0);
+        let svg = svg.finish();
+        assert_eq!(1, svg.matches("<path").count());
+        assert!(svg.contains(" 0 1 1 "));
+    }
+}
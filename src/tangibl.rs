@@ -3,15 +3,179 @@ use crate::{
         BooleanMethod, BooleanMethodKind, Command, Condition, Conditional, ConditionalKind, Flow,
         FlowKind, IntegerMethod, IntegerMethodKind, Start, Value,
     },
+    layout::Layout,
     parser::Parser,
+    raster, svg, ImageConfig, ImageError, LayoutError, ParseError, ParseReport, ParserConfig,
+    Program, Scene, SceneImage, SheetConfig, Token, TokenCode,
 };
 use std::collections::VecDeque;
 use topcodes::TopCode;
 
-pub fn parse(topcodes: &Vec<TopCode>) -> Option<Start> {
+pub fn parse(topcodes: &[TopCode]) -> Option<Start> {
     Parser::new(topcodes).parse()
 }
 
+/// Parses the TopCodes in the same way as [`parse`], but explains why no program could be
+/// produced instead of returning `None`.
+pub fn try_parse(topcodes: &[TopCode]) -> Result<Start, ParseError> {
+    Parser::new(topcodes).try_parse()
+}
+
+/// Parses the TopCodes in the same way as [`parse`], but additionally reports every problem
+/// found along the way, such as tokens which are not connected to the program.
+pub fn parse_report(topcodes: &[TopCode]) -> ParseReport {
+    Parser::new(topcodes).parse_report()
+}
+
+/// Parses the TopCodes using the given tolerances and token geometry instead of the defaults.
+pub fn parse_with(topcodes: &[TopCode], config: &ParserConfig) -> Option<Start> {
+    Parser::with_config(topcodes, config.clone()).parse()
+}
+
+/// Reports on the TopCodes in the same way as [`parse_report`], using the given config.
+pub fn parse_report_with(topcodes: &[TopCode], config: &ParserConfig) -> ParseReport {
+    Parser::with_config(topcodes, config.clone()).parse_report()
+}
+
+/// Parses the program in the same way as [`parse_report`], along with every chain of blocks which
+/// is not connected to it, so that they can be pointed out.
+pub fn parse_scene(topcodes: &[TopCode]) -> Scene {
+    Parser::new(topcodes).parse_scene()
+}
+
+/// Parses the scene in the same way as [`parse_scene`], using the given config.
+pub fn parse_scene_with(topcodes: &[TopCode], config: &ParserConfig) -> Scene {
+    Parser::with_config(topcodes, config.clone()).parse_scene()
+}
+
+/// Parses a separate program from every start token, e.g. when several players share a table.
+/// The programs are in reading order (top to bottom, then left to right) of their start tokens,
+/// and a token which could belong to more than one program goes to the one it fits best.
+pub fn parse_all_programs(topcodes: &[TopCode]) -> Vec<Program> {
+    Parser::new(topcodes).parse_all_programs()
+}
+
+/// Parses the programs in the same way as [`parse_all_programs`], using the given config.
+pub fn parse_all_programs_with(topcodes: &[TopCode], config: &ParserConfig) -> Vec<Program> {
+    Parser::with_config(topcodes, config.clone()).parse_all_programs()
+}
+
+/// Converts the TopCodes into tokens, dropping those which are not Tangibl tokens. This is the
+/// first stage of [`parse`], exposed so that tokens can be inspected or adjusted before parsing
+/// them with [`parse_tokens`].
+pub fn tokenize(topcodes: &[TopCode]) -> Vec<Token> {
+    tokenize_with(topcodes, &ParserConfig::default())
+}
+
+/// Converts the TopCodes into tokens in the same way as [`tokenize`], first correcting them for
+/// the camera described by the config (see [`ParserConfig::homography`] and
+/// [`ParserConfig::distortion`]).
+pub fn tokenize_with(topcodes: &[TopCode], config: &ParserConfig) -> Vec<Token> {
+    topcodes
+        .iter()
+        .filter_map(|topcode| Token::from_topcode(&config.correct(topcode)?))
+        .collect()
+}
+
+/// Parses tokens directly, e.g. from another detector or a replay file. The tokens must follow
+/// the convention described on [`Token`].
+pub fn parse_tokens(tokens: &[Token]) -> Option<Start> {
+    Parser::from_tokens(tokens, ParserConfig::default()).parse()
+}
+
+/// Reports on the tokens in the same way as [`parse_report`]. The spans refer to indices into the
+/// given tokens rather than TopCodes.
+pub fn parse_tokens_report(tokens: &[Token]) -> ParseReport {
+    Parser::from_tokens(tokens, ParserConfig::default()).parse_report()
+}
+
+/// Parses the tokens using the given config instead of the defaults.
+pub fn parse_tokens_with(tokens: &[Token], config: &ParserConfig) -> Option<Start> {
+    Parser::from_tokens(tokens, config.clone()).parse()
+}
+
+/// Reports on the tokens in the same way as [`parse_tokens_report`], using the given config.
+pub fn parse_tokens_report_with(tokens: &[Token], config: &ParserConfig) -> ParseReport {
+    Parser::from_tokens(tokens, config.clone()).parse_report()
+}
+
+/// Places the tokens of the program where [`parse`] expects to find them, so that parsing the
+/// TopCodes gives back the same program. The start token is at the origin, facing right.
+///
+/// Not every program can be laid out. Each token's position is fixed by the token it is linked
+/// from, so e.g. nested bodies which curl back on themselves would need two tokens in one place.
+pub fn layout(start: &Start) -> Result<Vec<TopCode>, LayoutError> {
+    layout_with(start, &ParserConfig::default())
+}
+
+/// Lays out the program in the same way as [`layout`], using the geometry and shapes of the given
+/// config. The TopCodes are in table coordinates, as the camera corrections are not reversed.
+pub fn layout_with(start: &Start, config: &ParserConfig) -> Result<Vec<TopCode>, LayoutError> {
+    let tokens = layout_tokens_with(start, config)?;
+    Ok(tokens.iter().map(Token::to_topcode).collect())
+}
+
+/// Lays out the program in the same way as [`layout`], as tokens rather than TopCodes.
+pub fn layout_tokens(start: &Start) -> Result<Vec<Token>, LayoutError> {
+    layout_tokens_with(start, &ParserConfig::default())
+}
+
+/// Lays out the program in the same way as [`layout_tokens`], using the given config.
+pub fn layout_tokens_with(start: &Start, config: &ParserConfig) -> Result<Vec<Token>, LayoutError> {
+    Layout::new(config).place(start)
+}
+
+/// Draws the TopCodes as an SVG document, along with how the parser linked them in the report: an
+/// arrow for every link, coloured by kind, and a dashed ghost wherever a token of the program
+/// expected another token which was not there. Tokens which are not part of the program are
+/// shaded grey, and TopCodes which are not tokens are left out. This is useful for explaining why
+/// a program did not parse as intended.
+///
+/// The report should come from parsing the same TopCodes, in the same order, e.g. with
+/// [`parse_report`]. TopCodes are matched to the program by [`crate::Span::index`], so the report
+/// may have been corrected for the camera. Tokens parsed with [`parse_tokens_report`] can be drawn
+/// by converting them with [`Token::to_topcode`]. The drawing is in image coordinates, one unit per
+/// pixel.
+pub fn render_svg(topcodes: &[TopCode], report: &ParseReport) -> String {
+    render_svg_with(topcodes, report, &ParserConfig::default())
+}
+
+/// Draws the TopCodes in the same way as [`render_svg`], using the geometry and shapes of the
+/// given config. See [`ParserConfig::shapes`] for how custom shapes are drawn.
+pub fn render_svg_with(
+    topcodes: &[TopCode],
+    report: &ParseReport,
+    config: &ParserConfig,
+) -> String {
+    let tokens = topcodes.iter().map(Token::from_topcode).collect::<Vec<_>>();
+    svg::render(&tokens, report, config)
+}
+
+/// Draws the tokens for printing, as SVG pages of A4 paper with tokens 40mm across. The TopCodes
+/// and outlines are drawn from the same geometry the parser uses, so printed tokens always parse.
+/// A code can be repeated to print several of the same token.
+pub fn token_sheets(codes: &[TokenCode]) -> Vec<String> {
+    token_sheets_with(codes, &SheetConfig::default())
+}
+
+/// Draws the tokens for printing in the same way as [`token_sheets`], with the given page size,
+/// token size and token geometry.
+pub fn token_sheets_with(codes: &[TokenCode], config: &SheetConfig) -> Vec<String> {
+    svg::sheets(codes, config)
+}
+
+/// Draws the tokens into a greyscale image, as a slightly blurry and noisy camera straight above
+/// the table would see them. Scanning the image with [`SceneImage::scan`] and parsing the TopCodes
+/// tests the whole pipeline from camera to program, without a camera.
+pub fn render_image(tokens: &[Token]) -> Result<SceneImage, ImageError> {
+    render_image_with(tokens, &ImageConfig::default())
+}
+
+/// Draws the tokens in the same way as [`render_image`], with the given camera and token geometry.
+pub fn render_image_with(tokens: &[Token], config: &ImageConfig) -> Result<SceneImage, ImageError> {
+    raster::render(tokens, config)
+}
+
 pub fn start() -> TangiblStartBuilder {
     TangiblStartBuilder::default()
 }
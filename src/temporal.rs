@@ -0,0 +1,367 @@
+use std::{
+    collections::{HashMap, HashSet},
+    f64::consts::PI,
+};
+
+use topcodes::TopCode;
+
+use crate::{
+    ast::Start,
+    parser::{Parser, Slot},
+    ParseReport, TemporalConfig,
+};
+
+/// A token which has been followed across frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    /// Identifies the token for as long as it stays in the scene.
+    pub id: u64,
+    /// The smoothed detection of the token, or its last detection while it is missing.
+    pub topcode: TopCode,
+    /// How many frames in a row the token has gone undetected.
+    pub missed: usize,
+}
+
+impl Track {
+    fn update(&mut self, topcode: &TopCode, smoothing: f64) {
+        let weight = smoothing.clamp(0.0, 1.0);
+        let current = &mut self.topcode;
+        current.x += (topcode.x - current.x) * weight;
+        current.y += (topcode.y - current.y) * weight;
+        current.unit += (topcode.unit - current.unit) * weight;
+        // Blending through the shortest turn, so that e.g. 359° and 1° average to 0°.
+        let turn = (topcode.orientation - current.orientation + PI).rem_euclid(PI * 2.0) - PI;
+        current.orientation += turn * weight;
+        self.missed = 0;
+    }
+}
+
+/// What a frame did to the stable program of a [`TemporalParser`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgramUpdate<'a> {
+    /// The stable program is the same as after the previous frame.
+    Unchanged,
+    /// The frames agreed on a different program, or on there being no program at all.
+    Changed(Option<&'a Start>),
+}
+
+/// Parses a stream of frames from a camera, where detections drop in and out and jitter from one
+/// frame to the next. Tokens are tracked across frames, links made in the previous frame are
+/// preferred over slightly better fits, and a program is only emitted once every frame has
+/// agreed on it for a while.
+pub struct TemporalParser {
+    config: TemporalConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+    /// The links made in the previous frame, as (owner, slot, target) track ids.
+    links: HashSet<(u64, Slot, u64)>,
+    /// The program parsed from the latest frames, and how many frames in a row produced it.
+    pending: Option<Start>,
+    pending_frames: usize,
+    current: Option<Start>,
+    report: ParseReport,
+}
+
+impl TemporalParser {
+    pub fn new() -> Self {
+        Self::with_config(TemporalConfig::default())
+    }
+
+    pub fn with_config(config: TemporalConfig) -> Self {
+        Self {
+            config,
+            tracks: Vec::new(),
+            next_id: 0,
+            links: HashSet::new(),
+            pending: None,
+            pending_frames: 0,
+            current: None,
+            report: ParseReport::default(),
+        }
+    }
+
+    /// Parses the next frame. The program changes once a different program, or no program, has
+    /// been parsed for [`TemporalConfig::stable_frames`] frames in a row, e.g. after the tokens
+    /// have been removed.
+    ///
+    /// A token which goes undetected is still parsed at its last position until it has been
+    /// missing for more than [`TemporalConfig::max_missed_frames`] frames, so that a hand passing
+    /// over the tokens does not change the program.
+    pub fn push_frame(&mut self, topcodes: &[TopCode]) -> ProgramUpdate<'_> {
+        self.track(topcodes);
+
+        let frame = self
+            .tracks
+            .iter()
+            .map(|track| track.topcode)
+            .collect::<Vec<_>>();
+        let indices = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| (track.id, index))
+            .collect::<HashMap<_, _>>();
+        let preferred = self
+            .links
+            .iter()
+            .filter_map(|(owner, slot, target)| {
+                Some((*indices.get(owner)?, *slot, *indices.get(target)?))
+            })
+            .collect::<Vec<_>>();
+        let (report, links) = Parser::with_config(&frame, self.config.parser.clone())
+            .prefer_links(preferred, self.config.link_hysteresis)
+            .parse_linked();
+        self.links = links
+            .into_iter()
+            .map(|(owner, slot, target)| (self.tracks[owner].id, slot, self.tracks[target].id))
+            .collect();
+
+        if report.start == self.pending {
+            self.pending_frames += 1;
+        } else {
+            self.pending = report.start.clone();
+            self.pending_frames = 1;
+        }
+        self.report = report;
+
+        if self.pending_frames >= self.config.stable_frames && self.pending != self.current {
+            self.current = self.pending.clone();
+            return ProgramUpdate::Changed(self.current.as_ref());
+        }
+        ProgramUpdate::Unchanged
+    }
+
+    /// The stable program, as of the last change returned by [`Self::push_frame`].
+    pub fn current(&self) -> Option<&Start> {
+        self.current.as_ref()
+    }
+
+    /// The report on the latest frame, whether or not it is stable yet. The spans index into
+    /// [`Self::tracks`] rather than the TopCodes of the frame.
+    pub fn latest_report(&self) -> &ParseReport {
+        &self.report
+    }
+
+    /// Every token currently in the scene, including those which have recently gone undetected.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Matches the detections to the existing tracks, closest first. Detections which match
+    /// nothing start new tracks, and tracks which have gone undetected for too long are dropped.
+    fn track(&mut self, topcodes: &[TopCode]) {
+        let mut pairs = Vec::new();
+        for (detection, topcode) in topcodes.iter().enumerate() {
+            for (index, track) in self.tracks.iter().enumerate() {
+                if track.topcode.code != topcode.code {
+                    continue;
+                }
+                let distance = (topcode.x - track.topcode.x).hypot(topcode.y - track.topcode.y);
+                if distance <= self.config.match_distance * track.topcode.unit * 8.0 {
+                    pairs.push((distance, detection, index));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+
+        let mut detected = vec![false; topcodes.len()];
+        let mut updated = vec![false; self.tracks.len()];
+        for (_, detection, index) in pairs {
+            if detected[detection] || updated[index] {
+                continue;
+            }
+            detected[detection] = true;
+            updated[index] = true;
+            self.tracks[index].update(&topcodes[detection], self.config.smoothing);
+        }
+
+        for (track, updated) in self.tracks.iter_mut().zip(updated) {
+            if !updated {
+                track.missed += 1;
+            }
+        }
+        let max_missed_frames = self.config.max_missed_frames;
+        self.tracks
+            .retain(|track| track.missed <= max_missed_frames);
+
+        for (topcode, detected) in topcodes.iter().zip(detected) {
+            if detected || topcode.code.is_none() {
+                continue;
+            }
+            self.tracks.push(Track {
+                id: self.next_id,
+                topcode: *topcode,
+                missed: 0,
+            });
+            self.next_id += 1;
+        }
+    }
+}
+
+impl Default for TemporalParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ast::Command, flow, TokenCode};
+
+    fn program_topcodes(jitter: f64) -> Vec<TopCode> {
+        vec![
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, jitter, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, jitter),
+            TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 200.0 - jitter, 0.0),
+        ]
+    }
+
+    fn program() -> Start {
+        Start {
+            next: flow()
+                .with_command(Command::Shoot)
+                .with_command(Command::TurnLeft)
+                .build(),
+        }
+    }
+
+    #[test]
+    fn it_waits_for_the_program_to_be_stable() {
+        let mut parser = TemporalParser::new();
+        for frame in 0..4 {
+            assert_eq!(
+                ProgramUpdate::Unchanged,
+                parser.push_frame(&program_topcodes(frame as f64))
+            );
+        }
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&program())),
+            parser.push_frame(&program_topcodes(2.0))
+        );
+        // Nothing new is emitted while the program stays the same.
+        assert_eq!(
+            ProgramUpdate::Unchanged,
+            parser.push_frame(&program_topcodes(1.0))
+        );
+        assert_eq!(Some(&program()), parser.current());
+    }
+
+    #[test]
+    fn it_signals_when_the_program_is_removed() {
+        let mut parser = TemporalParser::with_config(TemporalConfig {
+            stable_frames: 2,
+            max_missed_frames: 0,
+            ..Default::default()
+        });
+        parser.push_frame(&program_topcodes(0.0));
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&program())),
+            parser.push_frame(&program_topcodes(0.0))
+        );
+        assert_eq!(ProgramUpdate::Unchanged, parser.push_frame(&[]));
+        assert_eq!(ProgramUpdate::Changed(None), parser.push_frame(&[]));
+        assert_eq!(ProgramUpdate::Unchanged, parser.push_frame(&[]));
+        assert_eq!(None, parser.current());
+    }
+
+    #[test]
+    fn it_keeps_tokens_which_briefly_drop_out() {
+        let mut parser = TemporalParser::with_config(TemporalConfig {
+            stable_frames: 1,
+            ..Default::default()
+        });
+        let topcodes = program_topcodes(0.0);
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&program())),
+            parser.push_frame(&topcodes)
+        );
+        let ids = parser
+            .tracks()
+            .iter()
+            .map(|track| track.id)
+            .collect::<Vec<_>>();
+
+        assert_eq!(ProgramUpdate::Unchanged, parser.push_frame(&topcodes[..2]));
+        assert_eq!(
+            ProgramUpdate::Unchanged,
+            parser.push_frame(&program_topcodes(3.0))
+        );
+        assert_eq!(
+            ids,
+            parser
+                .tracks()
+                .iter()
+                .map(|track| track.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_parses_missing_tokens_until_their_grace_period_ends() {
+        let mut parser = TemporalParser::with_config(TemporalConfig {
+            stable_frames: 1,
+            ..Default::default()
+        });
+        let topcodes = program_topcodes(0.0);
+        parser.push_frame(&topcodes);
+
+        // The turn left token is parsed at its last position while it is missing.
+        for missed in 1..=3 {
+            assert_eq!(ProgramUpdate::Unchanged, parser.push_frame(&topcodes[..2]));
+            assert_eq!(missed, parser.tracks()[2].missed);
+            assert_eq!(topcodes[2], parser.tracks()[2].topcode);
+            assert_eq!(Some(program()), parser.latest_report().start);
+        }
+
+        // Gone for good.
+        let shoot = Start {
+            next: flow().with_command(Command::Shoot).build(),
+        };
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&shoot)),
+            parser.push_frame(&topcodes[..2])
+        );
+        assert_eq!(2, parser.tracks().len());
+    }
+
+    #[test]
+    fn it_keeps_links_between_close_candidates() {
+        // Two candidates for the same slot, each slightly closer in alternate frames.
+        let frame = |offset: f64| {
+            vec![
+                TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+                TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 100.0, 10.0 - offset),
+                TopCode::mock(TokenCode::TurnLeft.value(), 6.0, 0.0, 100.0, -10.0 - offset),
+            ]
+        };
+        let config = TemporalConfig {
+            smoothing: 1.0,
+            stable_frames: 1,
+            ..Default::default()
+        };
+        let shoot = Start {
+            next: flow().with_command(Command::Shoot).build(),
+        };
+
+        let mut parser = TemporalParser::with_config(config.clone());
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&shoot)),
+            parser.push_frame(&frame(1.0))
+        );
+        assert_eq!(ProgramUpdate::Unchanged, parser.push_frame(&frame(-1.0)));
+        assert_eq!(Some(&shoot), parser.current());
+
+        let mut parser = TemporalParser::with_config(TemporalConfig {
+            link_hysteresis: 0.0,
+            ..config
+        });
+        parser.push_frame(&frame(1.0));
+        assert_eq!(
+            ProgramUpdate::Changed(Some(&Start {
+                next: flow().with_command(Command::TurnLeft).build()
+            })),
+            parser.push_frame(&frame(-1.0))
+        );
+    }
+}
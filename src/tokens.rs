@@ -1,9 +1,13 @@
+use std::f64::consts::PI;
+
 #[cfg(test)]
 use enum_iterator::Sequence;
 use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+use topcodes::TopCode;
 
 #[cfg_attr(test, derive(Sequence))]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, TryFromPrimitive, Serialize, Deserialize)]
 #[repr(u32)]
 pub enum TokenCode {
     Start = 61,
@@ -47,6 +51,9 @@ impl TokenCode {
     }
 }
 
+/// A recognised TopCode, as seen by the parser. Unlike TopCodes, tokens follow mathematical
+/// convention: y increases upwards and orientation increases anticlockwise, in radians between 0
+/// and 2π. Tokens from another detector need to be converted to this convention first.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Token {
     pub code: TokenCode,
@@ -67,6 +74,41 @@ impl Token {
         }
     }
 
+    /// Converts a TopCode into a token, or `None` if its code is not a Tangibl token. The y value
+    /// and orientation are flipped from image convention, and the diameter is the TopCode's own.
+    pub fn from_topcode(topcode: &TopCode) -> Option<Self> {
+        let code = TokenCode::try_from(topcode.code?).ok()?;
+        Some(Self::new(
+            code,
+            topcode.unit * 8.0,
+            (-topcode.orientation).rem_euclid(PI * 2.0),
+            topcode.x,
+            -topcode.y,
+        ))
+    }
+
+    /// Converts the token back into the TopCode it would be scanned as, undoing
+    /// [`Self::from_topcode`].
+    pub fn to_topcode(&self) -> TopCode {
+        TopCode::mock(
+            self.code.value(),
+            self.diameter / 8.0,
+            (-self.orientation).rem_euclid(PI * 2.0),
+            self.x,
+            -self.y,
+        )
+    }
+
+    /// Whether the token can be placed in a scene at all, i.e. has a finite position and a
+    /// positive diameter.
+    pub fn is_well_formed(&self) -> bool {
+        self.x.is_finite()
+            && self.y.is_finite()
+            && self.orientation.is_finite()
+            && self.diameter.is_finite()
+            && self.diameter > 0.0
+    }
+
     /// Is part of the regular flow of the program. In other words, has a previous and next token.
     pub fn is_flow(&self) -> bool {
         matches!(
@@ -124,7 +166,6 @@ impl Token {
 mod tests {
     use super::*;
     use enum_iterator::all;
-    use topcodes::TopCode;
 
     #[test]
     fn tokencode_enum_values_are_valid_topcodes() {
@@ -133,4 +174,19 @@ mod tests {
             assert!(TopCode::checksum(token.value()) || token.value() == 0);
         }
     }
+
+    #[test]
+    fn it_flips_topcodes_into_mathematical_convention() {
+        let topcode = TopCode::mock(TokenCode::Shoot.value(), 6.0, PI / 2.0, 10.0, 20.0);
+        assert_eq!(
+            Some(Token::new(TokenCode::Shoot, 48.0, PI * 1.5, 10.0, -20.0)),
+            Token::from_topcode(&topcode)
+        );
+        assert_eq!(topcode, Token::from_topcode(&topcode).unwrap().to_topcode());
+        assert_eq!(None, Token::from_topcode(&TopCode::default()));
+        assert_eq!(
+            None,
+            Token::from_topcode(&TopCode::mock(5, 6.0, 0.0, 0.0, 0.0))
+        );
+    }
 }
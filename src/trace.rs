@@ -0,0 +1,56 @@
+use serde::Serialize;
+
+use crate::{Slot, Span};
+
+/// Every lookup the parser made while linking the tokens, so that tools can show why a link was
+/// or was not made. Enabled by [`crate::ParserConfig::trace`]. Positions and angles are in image
+/// coordinates, like [`Span`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ParseTrace {
+    pub lookups: Vec<LookupTrace>,
+}
+
+/// A search for the token in one of the slots of the owner.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LookupTrace {
+    pub owner: Span,
+    pub slot: Slot,
+    pub expected_x: f64,
+    pub expected_y: f64,
+    /// The orientation the token in the slot is expected to have, in radians.
+    pub expected_angle: f64,
+    /// Every token near the expected position which could go in the slot, best fit first.
+    pub candidates: Vec<CandidateTrace>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidateTrace {
+    pub token: Span,
+    /// The distance between the token and the expected position.
+    pub displacement: f64,
+    /// The difference between the orientation of the token and the expected angle, in radians.
+    pub angle_error: f64,
+    pub verdict: Verdict,
+}
+
+/// What became of a candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Verdict {
+    /// The candidate was linked into the slot.
+    Linked,
+    /// The candidate was too far from the expected position or angle.
+    OutOfTolerance,
+    /// A better fitting candidate was linked into the slot.
+    SlotTaken,
+    /// The candidate was linked into a slot it fit better, e.g. of another token.
+    LinkedElsewhere,
+}
+
+impl ParseTrace {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("a trace is always valid JSON")
+    }
+}
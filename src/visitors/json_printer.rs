@@ -1,11 +1,11 @@
-use serde_json::{Map, Value as JsValue};
+use serde_json::{json, Map, Value as JsValue};
 
 use crate::{
     ast::{
         BooleanMethod, BooleanMethodKind, Command, Condition, Conditional, ConditionalKind, Flow,
         FlowKind, IntegerMethod, IntegerMethodKind, Start, Value,
     },
-    Visitor,
+    NodeId, SourceMap, Visitor,
 };
 
 const NAME: &str = "name";
@@ -14,49 +14,141 @@ const ALTERNATE: &str = "alternate";
 const CONDITION: &str = "condition";
 const BODY: &str = "body";
 const VALUE: &str = "value";
+const SPAN: &str = "span";
+const CONDITION_SPAN: &str = "conditionSpan";
+const VALUE_SPAN: &str = "valueSpan";
 
 /// The JsonPrinter can be used to produce a tree for communicating over a C dynamic library
 /// bridge. This can be ignored for Rust development, but will be useful for interop between
 /// languages.
 ///
 /// The printer implements the visitor pattern on the internal representation of the AST. Since
-/// Rust uses algebraic enum types, we don't need to implement structs for each token.
-pub struct JsonPrinter {}
+/// Rust uses algebraic enum types, we don't need to implement structs for each token. Visiting a
+/// node produces it along with everything below it, while [`JsonPrinter::print`] builds the same
+/// tree without recursing, so that programs of any length can be printed.
+pub struct JsonPrinter {
+    /// The spans to include with each node, if any.
+    spans: Option<SourceMap>,
+    /// The id of the next node to be visited, in pre-order.
+    next_id: usize,
+}
 
 impl JsonPrinter {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            spans: None,
+            next_id: 0,
+        }
+    }
+
+    /// Creates a printer which includes the span of every node, e.g. from
+    /// [`crate::ParseReport::spans`], under a "span" key. The spans of method parameters are
+    /// under "conditionSpan" and "valueSpan".
+    pub fn with_spans(spans: SourceMap) -> Self {
+        Self {
+            spans: Some(spans),
+            next_id: 0,
+        }
     }
 
     pub fn print(&mut self, start: &Start) -> String {
-        self.visit_start(start).to_string()
+        let mut output = String::new();
+        let mut pending = Vec::new();
+        let fields = self.start_fields();
+        Self::push_object(
+            fields,
+            start.next.as_ref().map(|next| (NEXT, next)),
+            &mut pending,
+        );
+        while let Some(item) = pending.pop() {
+            match item {
+                Item::Text(text) => output.push_str(&text),
+                Item::Flow(flow) => {
+                    let fields = self.flow_fields(flow);
+                    let branch_key = match flow.kind {
+                        FlowKind::Conditional(_) => ALTERNATE,
+                        _ => BODY,
+                    };
+                    let children = flow
+                        .kind
+                        .branch()
+                        .map(|branch| (branch_key, branch))
+                        .into_iter()
+                        .chain(flow.next.as_deref().map(|next| (NEXT, next)));
+                    Self::push_object(fields, children, &mut pending);
+                }
+            }
+        }
+        output
     }
-}
 
-impl Default for JsonPrinter {
-    fn default() -> Self {
-        Self::new()
+    /// Schedules an object made from the fields of a node and its children, with the keys in
+    /// sorted order. The branch ("alternate" or "body") sorts before "next", so the nodes are
+    /// visited in pre-order.
+    fn push_object<'a>(
+        fields: Map<String, JsValue>,
+        children: impl IntoIterator<Item = (&'static str, &'a Flow)>,
+        pending: &mut Vec<Item<'a>>,
+    ) {
+        let mut entries = fields
+            .into_iter()
+            .map(|(key, value)| (key, Item::Text(value.to_string())))
+            .chain(
+                children
+                    .into_iter()
+                    .map(|(key, flow)| (key.to_string(), Item::Flow(flow))),
+            )
+            .collect::<Vec<_>>();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut items = vec![Item::Text("{".into())];
+        for (i, (key, value)) in entries.into_iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            items.push(Item::Text(format!(
+                "{}{}:",
+                separator,
+                JsValue::String(key)
+            )));
+            items.push(value);
+        }
+        items.push(Item::Text("}".into()));
+        pending.extend(items.into_iter().rev());
     }
-}
 
-impl Visitor for JsonPrinter {
-    type Result = JsValue;
+    /// Inserts the span of the next node in pre-order, if spans are being printed.
+    fn insert_span(&mut self, map: &mut Map<String, JsValue>, key: &str) {
+        let id = NodeId(self.next_id);
+        self.next_id += 1;
+        if let Some(span) = self.spans.as_ref().and_then(|spans| spans.get(id)) {
+            map.insert(key.into(), json!(span));
+        }
+    }
 
-    fn visit_start(&mut self, start: &Start) -> Self::Result {
+    /// The fields of the start node, without the nodes after it.
+    fn start_fields(&mut self) -> Map<String, JsValue> {
         let mut map = Map::new();
         map.insert(NAME.into(), JsValue::String("start".into()));
+        self.next_id = 0;
+        self.insert_span(&mut map, SPAN);
+        map
+    }
 
-        if let Some(flow) = &start.next {
-            let next = self.visit_flow(flow);
-            if next.is_object() {
-                map.insert(NEXT.into(), next);
-            }
-        }
-
-        JsValue::Object(map)
+    /// The fields of a flow node, without its branch or the nodes after it.
+    fn flow_fields(&mut self, flow: &Flow) -> Map<String, JsValue> {
+        // The span is taken before the parameter so that ids stay in pre-order.
+        let mut span = Map::new();
+        self.insert_span(&mut span, SPAN);
+        let mut map = match &flow.kind {
+            FlowKind::Command(command) => Self::command_fields(command),
+            FlowKind::Conditional(conditional) => Self::conditional_fields(conditional),
+            FlowKind::BooleanMethod(boolean_method) => self.boolean_method_fields(boolean_method),
+            FlowKind::IntegerMethod(integer_method) => self.integer_method_fields(integer_method),
+        };
+        map.extend(span);
+        map
     }
 
-    fn visit_command(&mut self, command: &Command) -> Self::Result {
+    fn command_fields(command: &Command) -> Map<String, JsValue> {
         let mut map = Map::new();
         let name = match &command {
             Command::Shoot => "shoot",
@@ -66,25 +158,19 @@ impl Visitor for JsonPrinter {
             Command::TurnRight => "turnRight",
         };
         map.insert(NAME.into(), name.into());
-        JsValue::Object(map)
+        map
     }
 
-    fn visit_conditional(&mut self, conditional: &Conditional) -> Self::Result {
+    fn conditional_fields(conditional: &Conditional) -> Map<String, JsValue> {
         let mut map = Map::new();
         let name = match &conditional.kind {
             ConditionalKind::Blocked => "blocked",
         };
         map.insert(NAME.into(), name.into());
-        if let Some(alternate_flow) = &conditional.alternate {
-            let alternate = self.visit_flow(alternate_flow);
-            if alternate.is_object() {
-                map.insert(ALTERNATE.into(), alternate);
-            }
-        }
-        JsValue::Object(map)
+        map
     }
 
-    fn visit_boolean_method(&mut self, boolean_method: &BooleanMethod) -> Self::Result {
+    fn boolean_method_fields(&mut self, boolean_method: &BooleanMethod) -> Map<String, JsValue> {
         let mut map = Map::new();
         let name = match &boolean_method.kind {
             BooleanMethodKind::While => "while",
@@ -96,17 +182,12 @@ impl Visitor for JsonPrinter {
                 Condition::IsPathClear => "isPathClear",
             };
             map.insert(CONDITION.into(), condition.into());
+            self.insert_span(&mut map, CONDITION_SPAN);
         }
-        if let Some(body_flow) = &boolean_method.body {
-            let body = self.visit_flow(body_flow);
-            if body.is_object() {
-                map.insert(BODY.into(), body);
-            }
-        }
-        JsValue::Object(map)
+        map
     }
 
-    fn visit_integer_method(&mut self, integer_method: &IntegerMethod) -> Self::Result {
+    fn integer_method_fields(&mut self, integer_method: &IntegerMethod) -> Map<String, JsValue> {
         let mut map = Map::new();
         let name = match &integer_method.kind {
             IntegerMethodKind::Repeat => "repeat",
@@ -125,29 +206,82 @@ impl Visitor for JsonPrinter {
                 Value::Infinity => "Infinity",
             };
             map.insert(VALUE.into(), value.into());
+            self.insert_span(&mut map, VALUE_SPAN);
         }
+        map
+    }
+}
+
+/// Part of the output which has not been printed yet.
+enum Item<'a> {
+    Text(String),
+    Flow(&'a Flow),
+}
+
+impl Default for JsonPrinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Visitor for JsonPrinter {
+    type Result = JsValue;
+
+    fn visit_start(&mut self, start: &Start) -> Self::Result {
+        let mut map = self.start_fields();
+        if let Some(flow) = &start.next {
+            let next = self.visit_flow(flow);
+            map.insert(NEXT.into(), next);
+        }
+        JsValue::Object(map)
+    }
+
+    fn visit_command(&mut self, command: &Command) -> Self::Result {
+        JsValue::Object(Self::command_fields(command))
+    }
+
+    fn visit_conditional(&mut self, conditional: &Conditional) -> Self::Result {
+        let mut map = Self::conditional_fields(conditional);
+        if let Some(alternate_flow) = &conditional.alternate {
+            let alternate = self.visit_flow(alternate_flow);
+            map.insert(ALTERNATE.into(), alternate);
+        }
+        JsValue::Object(map)
+    }
+
+    fn visit_boolean_method(&mut self, boolean_method: &BooleanMethod) -> Self::Result {
+        let mut map = self.boolean_method_fields(boolean_method);
+        if let Some(body_flow) = &boolean_method.body {
+            let body = self.visit_flow(body_flow);
+            map.insert(BODY.into(), body);
+        }
+        JsValue::Object(map)
+    }
+
+    fn visit_integer_method(&mut self, integer_method: &IntegerMethod) -> Self::Result {
+        let mut map = self.integer_method_fields(integer_method);
         if let Some(body_flow) = &integer_method.body {
             let body = self.visit_flow(body_flow);
-            if body.is_object() {
-                map.insert(BODY.into(), body);
-            }
+            map.insert(BODY.into(), body);
         }
         JsValue::Object(map)
     }
 
     fn visit_flow(&mut self, flow: &Flow) -> Self::Result {
+        // The span is taken before visiting the children so that ids stay in pre-order.
+        let mut span = Map::new();
+        self.insert_span(&mut span, SPAN);
         let mut node = match &flow.kind {
             FlowKind::Command(command) => self.visit_command(command),
             FlowKind::BooleanMethod(boolean_method) => self.visit_boolean_method(boolean_method),
             FlowKind::IntegerMethod(integer_method) => self.visit_integer_method(integer_method),
             FlowKind::Conditional(conditional) => self.visit_conditional(conditional),
         };
-        if let Some(next_flow) = &flow.next {
-            if let Some(node_mut) = node.as_object_mut() {
+        if let Some(node_mut) = node.as_object_mut() {
+            node_mut.extend(span);
+            if let Some(next_flow) = &flow.next {
                 let next = self.visit_flow(next_flow);
-                if next.is_object() {
-                    node_mut.insert("next".into(), next);
-                }
+                node_mut.insert(NEXT.into(), next);
             }
         }
         node
@@ -157,7 +291,8 @@ impl Visitor for JsonPrinter {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{flow, start};
+    use crate::{flow, start, TokenCode};
+    use topcodes::TopCode;
 
     #[test]
     fn it_can_print_a_complex_tree() {
@@ -186,4 +321,70 @@ mod tests {
         let expected = r#"{"name":"start","next":{"name":"shoot","next":{"alternate":{"name":"turnLeft"},"name":"blocked","next":{"name":"moveBackwards","next":{"body":{"name":"turnRight"},"condition":"isBlocked","name":"while","next":{"name":"moveForwards","next":{"body":{"name":"turnLeft"},"name":"repeat","next":{"name":"moveForwards"},"value":"3"}}}}}}}"#;
         assert_eq!(expected, json_printer.print(&ast));
     }
+
+    #[test]
+    fn it_can_print_spans() {
+        let report = crate::parse_report(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Value3.value(), 6.0, 0.0, 145.0, 100.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+        ]);
+        let mut json_printer = JsonPrinter::with_spans(report.spans);
+
+        let expected = r#"{"name":"start","next":{"name":"repeat","span":{"code":91,"diameter":48.0,"index":2,"orientation":0.0,"x":100.0,"y":0.0},"value":"3","valueSpan":{"code":107,"diameter":48.0,"index":1,"orientation":0.0,"x":145.0,"y":100.0}},"span":{"code":61,"diameter":48.0,"index":0,"orientation":0.0,"x":0.0,"y":0.0}}"#;
+        assert_eq!(expected, json_printer.print(&report.start.unwrap()));
+    }
+
+    #[test]
+    fn it_visits_the_same_tree_as_it_prints() {
+        let ast = start()
+            .with_integer_method(
+                IntegerMethodKind::Repeat,
+                Some(Value::Two),
+                flow()
+                    .with_conditional(Conditional {
+                        kind: ConditionalKind::Blocked,
+                        alternate: flow()
+                            .with_boolean_method(
+                                BooleanMethodKind::While,
+                                Some(Condition::IsPathClear),
+                                flow().with_command(Command::MoveForwards).build(),
+                            )
+                            .build()
+                            .map(Box::new),
+                    })
+                    .with_command(Command::TurnLeft)
+                    .build(),
+            )
+            .with_command(Command::Shoot)
+            .build();
+        let visited = JsonPrinter::new().visit_start(&ast);
+        assert_eq!(JsonPrinter::new().print(&ast), visited.to_string());
+        assert_eq!(Some("shoot"), visited["next"]["next"]["name"].as_str());
+
+        let report = crate::parse_report(&[
+            TopCode::mock(TokenCode::Start.value(), 6.0, 0.0, 0.0, 0.0),
+            TopCode::mock(TokenCode::Value3.value(), 6.0, 0.0, 145.0, 100.0),
+            TopCode::mock(TokenCode::Repeat.value(), 6.0, 0.0, 100.0, 0.0),
+            TopCode::mock(TokenCode::Shoot.value(), 6.0, 0.0, 200.0, 0.0),
+        ]);
+        let start = report.start.unwrap();
+        let visited = JsonPrinter::with_spans(report.spans.clone()).visit_start(&start);
+        assert_eq!(
+            JsonPrinter::with_spans(report.spans).print(&start),
+            visited.to_string()
+        );
+    }
+
+    #[test]
+    fn it_can_print_a_long_chain() {
+        let mut builder = start();
+        for _ in 0..100_000 {
+            builder.with_command(Command::Shoot);
+        }
+        let json = JsonPrinter::new().print(&builder.build());
+        assert!(json.starts_with(r#"{"name":"start","next":{"name":"shoot","next":"#));
+        assert_eq!(100_000, json.matches("shoot").count());
+        assert!(json.ends_with(&"}".repeat(100_001)));
+    }
 }